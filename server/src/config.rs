@@ -0,0 +1,325 @@
+//! Module containing the configuration of the server, read from a TOML file.
+//!
+//! ```no_run
+//! data_directory = "/var/lib/rusty-brew"
+//!
+//! [retention]
+//! compress = true
+//! max_age_days = 365
+//! max_total_size = 1000000000
+//! archive = "archive"
+//!
+//! [sensors]
+//! directory = "/sys/bus/w1/devices"
+//!
+//! [sensors.aliases]
+//! mash_probe = "28-000009eab19f"
+//!
+//! [resources.mash]
+//! frequency = 1
+//! sensor = { type = "ds18b20", id = "mash_probe", retries = 3 }
+//! output = { type = "led", up_pin = 20, down_pin = 21, pulse_width = 50 }
+//! pid = { kp = 7.0, ki = 0.6, kd = 0.0, max_integrator = 35.0 }
+//! limits = { max_temp = 80.0 }
+//! sensor_failure = { hold_ticks = 5 }
+//!
+//! [resources.simulated]
+//! frequency = 60
+//! sensor = { type = "mock" }
+//! output = { type = "mock" }
+//! ```
+//! Every setting is optional, except the frequency, sensor and output of a
+//! resource. Relative paths in the config file are relative to the directory of
+//! the config file, except the archive, which is relative to the data directory.
+//! A DS18B20 can be given by its id, or by an alias from [sensors.aliases], so
+//! only the alias must be changed when a probe is replaced.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use controller::{Controller, MAX_FREQUENCY};
+use controller::calibration::{CalibratedSensor, CalibrationMap};
+use controller::sensor::{Sensor, FailurePolicy};
+use controller::output::Output;
+use controller::ds18b20;
+use controller::ds18b20::{DS18B20, DEFAULT_RETRIES, DEFAULT_DEVICE_DIRECTORY};
+use controller::led::LedOutput;
+use controller::mock::{MockInternalState, MockTemperatureSensor, MockOutput};
+use controller::pid::PidParameters;
+use controller::validation::Limits;
+use log::retention::RetentionPolicy;
+use storage::Name;
+
+/// The environment variable giving the path of the config file.
+pub const CONFIG_VARIABLE: &str = "BREW_CONFIG";
+
+/// The environment variable giving the data directory.
+pub const DATA_DIRECTORY_VARIABLE: &str = "BREW_DATA_DIR";
+
+/// The config file used if no other config file is given. It is not an error
+/// if it doesn't exist.
+pub const DEFAULT_CONFIG: &str = "brew.toml";
+
+/// The settings of the server.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The directory logs and reference series are stored in. The current
+    /// directory is used if None.
+    pub data_directory: Option<PathBuf>,
+    pub retention: RetentionPolicy,
+    pub sensors: SensorsConfig,
+    /// The controllers exposed by the server, by name.
+    pub resources: BTreeMap<String, ResourceConfig>,
+}
+
+/// The onewire sensors, shared by every resource.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorsConfig {
+    /// The directory the kernel lists onewire devices in. DEFAULT_DEVICE_DIRECTORY
+    /// is used if None.
+    pub directory: Option<PathBuf>,
+    /// Names that can be used instead of the id of a DS18B20, by alias.
+    pub aliases: BTreeMap<String, String>,
+}
+
+/// The settings of a single controller.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceConfig {
+    pub sensor: SensorConfig,
+    pub output: OutputConfig,
+    /// The frequency the controller is running on, in Hz, as used by
+    /// Controller::new. At most MAX_FREQUENCY.
+    pub frequency: u64,
+    #[serde(default)]
+    pub pid: PidParameters,
+    #[serde(default)]
+    pub limits: Limits,
+    /// What the controller does when the sensor fails.
+    #[serde(default)]
+    pub sensor_failure: FailurePolicy,
+}
+
+/// The sensor of a controller. Encoded with its type as the field "type".
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SensorConfig {
+    /// Simulated temperature, heated by a mock output of the same resource.
+    Mock,
+    /// id is the name of the device, as found in the directory of the
+    /// SensorsConfig, or an alias of it. retries is the number of times a failed reading is retried.
+    Ds18b20 {
+        id: String,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+}
+
+/// The output of a controller. Encoded with its type as the field "type".
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum OutputConfig {
+    /// Simulated heater, heating a mock sensor of the same resource.
+    Mock,
+    /// See LedOutput::new.
+    Led { up_pin: u64, down_pin: u64, pulse_width: u64 },
+}
+
+impl Config {
+    /// Reads a config file. Relative paths are made relative to the directory
+    /// of the config file.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err( |e| {
+            io::Error::new(e.kind(), format!("Unable to read {}: {}", path.display(), e))
+        })?;
+        let mut config: Config = toml::from_str(&contents).map_err( |e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid config {}: {}", path.display(), e))
+        })?;
+
+        let directory = path.parent().unwrap_or(Path::new("."));
+        config.data_directory = config.data_directory.map( |data| directory.join(data) );
+        config.sensors.directory = config.sensors.directory.map( |devices| directory.join(devices) );
+        Ok(config)
+    }
+
+    /// Checks that every resource can be built, without touching any hardware.
+    /// The error tells which resource is wrong.
+    pub fn validate(&self) -> io::Result<()> {
+        for (alias, id) in &self.sensors.aliases {
+            if !id.starts_with(ds18b20::ID_PREFIX) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Sensor alias {}: {} is not the id of a DS18B20",
+                                                  alias, id)));
+            }
+        }
+        for (name, resource) in &self.resources {
+            // The name is used in URLs and in the names of logs
+            match Name::new(name) {
+                Ok(ref valid) if valid.as_str() == name => {},
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("Invalid resource name: {:?}", name))),
+            }
+            resource.validate(&self.sensors).map_err( |e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Resource {}: {}", name, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Makes the controllers of every resource, by name, using the given
+    /// calibrations of the sensors. Fails if the config is not valid.
+    pub fn build_resources(&self, calibrations: &CalibrationMap)
+                           -> io::Result<HashMap<String, Mutex<Controller>>> {
+        self.validate()?;
+        Ok(self.resources.iter()
+           .map( |(name, resource)| {
+               (name.clone(), Mutex::new(resource.build(&self.sensors, calibrations)))
+           })
+           .collect())
+    }
+
+    /// Finds and reads the config file. The path given on the command line is
+    /// used if there is one, then the path in the environment variable
+    /// CONFIG_VARIABLE. If neither is given, DEFAULT_CONFIG is read if it
+    /// exists, and the default config is used if it doesn't.
+    pub fn load(path: Option<PathBuf>) -> io::Result<Config> {
+        let path = path.or_else( || env::var_os(CONFIG_VARIABLE).map(PathBuf::from) );
+        match path {
+            Some(path) => Config::read(path),
+            None if Path::new(DEFAULT_CONFIG).exists() => Config::read(DEFAULT_CONFIG),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Returns the data directory to use. The directory given on the command
+    /// line is used if there is one, then the directory in the environment
+    /// variable DATA_DIRECTORY_VARIABLE, then the directory in the config file,
+    /// and the current directory if none of them are given.
+    pub fn get_data_directory(&self, path: Option<PathBuf>) -> PathBuf {
+        path.or_else( || env::var_os(DATA_DIRECTORY_VARIABLE).map(PathBuf::from) )
+            .or_else( || self.data_directory.clone() )
+            .unwrap_or_else( || PathBuf::from(".") )
+    }
+
+    /// Returns the retention policy, with the archive made relative to the
+    /// data directory.
+    pub fn get_retention_policy(&self, data_directory: &Path) -> RetentionPolicy {
+        let mut policy = self.retention.clone();
+        policy.archive = policy.archive.map( |archive| data_directory.join(archive) );
+        policy
+    }
+}
+
+impl ResourceConfig {
+    /// Checks the settings of the resource, without touching any hardware.
+    fn validate(&self, sensors: &SensorsConfig) -> Result<(), String> {
+        if let SensorConfig::Ds18b20 { ref id, .. } = self.sensor {
+            if !sensors.resolve(id).starts_with(ds18b20::ID_PREFIX) {
+                return Err(format!("{} is neither the id of a DS18B20 nor a sensor alias", id));
+            }
+        }
+        if self.frequency == 0 {
+            return Err("The frequency must be positive".to_owned());
+        }
+        if self.frequency > MAX_FREQUENCY {
+            return Err(format!("The frequency must be at most {} Hz", MAX_FREQUENCY));
+        }
+        let (kp, ki, kd) = self.pid.get_parameters();
+        if !(kp.is_finite() && ki.is_finite() && kd.is_finite()) {
+            return Err("The PID parameters must be finite".to_owned());
+        }
+        if !(self.limits.min_temp < self.limits.max_temp) {
+            return Err("min_temp must be below max_temp".to_owned());
+        }
+        if self.limits.min_duration > self.limits.max_duration {
+            return Err("min_duration must not be above max_duration".to_owned());
+        }
+        if let OutputConfig::Led { up_pin, down_pin, .. } = self.output {
+            if up_pin == down_pin {
+                return Err("up_pin and down_pin must be different pins".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the controller of the resource. A mock sensor and a mock output
+    /// of the same resource share the same simulated process.
+    fn build(&self, sensors: &SensorsConfig, calibrations: &CalibrationMap) -> Controller {
+        let mock_state = MockInternalState::new();
+        let mut controller = Controller::new(self.sensor.build(sensors, calibrations, &mock_state),
+                                             self.output.build(&mock_state),
+                                             self.pid.clone(),
+                                             self.frequency);
+        controller.set_limits(self.limits.clone());
+        controller.set_failure_policy(self.sensor_failure.clone());
+        controller
+    }
+}
+
+impl SensorsConfig {
+    /// Returns the directory the kernel lists onewire devices in.
+    pub fn get_directory(&self) -> PathBuf {
+        self.directory.clone().unwrap_or_else( || PathBuf::from(DEFAULT_DEVICE_DIRECTORY) )
+    }
+
+    /// Returns the id of the DS18B20 with the given alias, or the given id if
+    /// it isn't an alias.
+    pub fn resolve<'a>(&'a self, id: &'a str) -> &'a str {
+        self.aliases.get(id).map_or(id, |id| id.as_str())
+    }
+
+    /// Makes the DS18B20 with the given id or alias.
+    pub fn build_ds18b20(&self, id: &str) -> DS18B20 {
+        let mut sensor = DS18B20::new(self.resolve(id).to_owned());
+        sensor.set_device_directory(self.get_directory());
+        sensor
+    }
+
+    /// Returns the aliases of the DS18B20 with the given id.
+    pub fn get_aliases(&self, id: &str) -> Vec<String> {
+        self.aliases.iter()
+            .filter( |&(_, aliased)| aliased == id )
+            .map( |(alias, _)| alias.clone() )
+            .collect()
+    }
+}
+
+impl SensorConfig {
+    /// Makes the sensor. A mock sensor measures the given simulated process,
+    /// and a DS18B20 is found as given by sensors, and corrected by its calibration.
+    pub fn build(&self, sensors: &SensorsConfig, calibrations: &CalibrationMap,
+                 mock_state: &MockInternalState) -> Box<Sensor> {
+        match *self {
+            SensorConfig::Mock => Box::new(MockTemperatureSensor::new(mock_state.clone())),
+            SensorConfig::Ds18b20 { ref id, retries } => {
+                let mut sensor = sensors.build_ds18b20(id);
+                sensor.set_retries(retries);
+                let id = sensors.resolve(id).to_owned();
+                Box::new(CalibratedSensor::new(sensor, id, Arc::clone(calibrations)))
+            },
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Makes the output. A mock output heats the given simulated process.
+    pub fn build(&self, mock_state: &MockInternalState) -> Box<Output> {
+        match *self {
+            OutputConfig::Mock => Box::new(MockOutput::new(mock_state.clone())),
+            OutputConfig::Led { up_pin, down_pin, pulse_width } =>
+                Box::new(LedOutput::new(up_pin, down_pin, pulse_width)),
+        }
+    }
+}
+
+/// Helper function giving the default number of retries of a DS18B20.
+fn default_retries() -> u32 {
+    DEFAULT_RETRIES
+}
@@ -0,0 +1,218 @@
+//! Module containing the calibration of sensors. A CalibratedSensor corrects
+//! the temperatures read by another sensor, using the Calibration of the id of
+//! the sensor. Calibrations are kept in storage, and shared through a
+//! CalibrationMap, so a new calibration is used by running controllers at once.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use super::sensor::{Sensor, SensorError};
+use storage::Storage;
+
+/// The calibrations of every sensor, by sensor id.
+pub type CalibrationMap = Arc<RwLock<HashMap<String, Calibration>>>;
+
+/// Function reading every calibration in storage.
+pub fn load_calibrations(storage: &Storage) -> io::Result<CalibrationMap> {
+    let mut calibrations = HashMap::new();
+    for sensor in storage.get_list_of_calibrations()? {
+        let calibration = storage.get_calibration(&sensor)?;
+        calibrations.insert(sensor.as_str().to_owned(), calibration);
+    }
+    Ok(Arc::new(RwLock::new(calibrations)))
+}
+
+/// The correction of the temperatures read by a sensor, in degrees Celsius.
+/// Without any points, a temperature t is corrected to gain * t + offset.
+/// With a single point, the temperature is shifted by the error at the point.
+/// With more points, the temperature is interpolated linearly between the two
+/// nearest points, or extrapolated from the two first or last points.
+/// The offset and gain can not be combined with points, so they must be left
+/// at 0 and 1 when there are any.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Calibration {
+    pub offset: f32,
+    pub gain: f32,
+    /// Sorted by the temperature read, which is different for every point.
+    pub points: Vec<CalibrationPoint>,
+}
+
+/// The sensor read the temperature read when the real temperature was reference.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    pub read: f32,
+    pub reference: f32,
+}
+
+/// offset = 0, gain = 1, and no points, which leaves temperatures unchanged.
+impl Default for Calibration {
+    fn default() -> Calibration {
+        Calibration {
+            offset: 0.0,
+            gain: 1.0,
+            points: Vec::new(),
+        }
+    }
+}
+
+impl Calibration {
+    /// Returns the corrected temperature.
+    pub fn apply(&self, temperature: f32) -> f32 {
+        let points = &self.points;
+        match points.len() {
+            0 => self.gain * temperature + self.offset,
+            1 => temperature + points[0].reference - points[0].read,
+            n => {
+                // The segment ending with the first point above the temperature
+                let end = points.iter().position( |point| point.read > temperature )
+                    .unwrap_or(n).max(1).min(n - 1);
+                let (a, b) = (points[end - 1], points[end]);
+                a.reference + (temperature - a.read) * (b.reference - a.reference) / (b.read - a.read)
+            },
+        }
+    }
+
+    /// Adds a point, which replaces any point with the same reference or the
+    /// same temperature read, so a point can be measured again.
+    pub fn add_point(&mut self, point: CalibrationPoint) {
+        self.points.retain( |p| p.reference != point.reference && p.read != point.read );
+        let index = self.points.iter().position( |p| p.read > point.read )
+            .unwrap_or(self.points.len());
+        self.points.insert(index, point);
+    }
+
+    /// Checks that the calibration can be used.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.offset.is_finite() && self.gain.is_finite() && self.gain > 0.0) {
+            return Err("The offset must be finite, and the gain positive".to_owned());
+        }
+        if !self.points.is_empty() && (self.offset != 0.0 || self.gain != 1.0) {
+            return Err("The offset and gain can not be combined with points".to_owned());
+        }
+        for point in &self.points {
+            if !(point.read.is_finite() && point.reference.is_finite()) {
+                return Err("The temperatures of the points must be finite".to_owned());
+            }
+        }
+        for pair in self.points.windows(2) {
+            if pair[0].read >= pair[1].read {
+                return Err("The points must be sorted by the temperature read, \
+                            which must be different for every point".to_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A Sensor whose temperatures are corrected by the calibration of its id, if
+/// it has one.
+pub struct CalibratedSensor<S: Sensor> {
+    sensor: S,
+    id: String,
+    calibrations: CalibrationMap,
+}
+
+impl<S: Sensor> CalibratedSensor<S> {
+    /// Constructor for CalibratedSensor. id is the id the calibration of the
+    /// sensor is stored by, such as the id of a DS18B20.
+    pub fn new(sensor: S, id: String, calibrations: CalibrationMap) -> CalibratedSensor<S> {
+        CalibratedSensor { sensor, id, calibrations }
+    }
+}
+
+impl<S: Sensor> Sensor for CalibratedSensor<S> {
+    fn read(&self) -> Result<f32, SensorError> {
+        let temperature = self.sensor.read()?;
+        let calibrations = self.calibrations.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(match calibrations.get(&self.id) {
+            Some(calibration) => calibration.apply(temperature),
+            None => temperature,
+        })
+    }
+
+    /// The type of the sensor, marked as calibrated if it has a calibration.
+    fn get_type(&self) -> String {
+        let calibrations = self.calibrations.read().unwrap_or_else(PoisonError::into_inner);
+        match calibrations.get(&self.id) {
+            Some(_) => format!("{} (calibrated)", self.sensor.get_type()),
+            None => self.sensor.get_type(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(read: f32, reference: f32) -> CalibrationPoint {
+        CalibrationPoint { read, reference }
+    }
+
+    fn with_points(points: Vec<CalibrationPoint>) -> Calibration {
+        Calibration { points, ..Calibration::default() }
+    }
+
+    #[test]
+    fn applies_offset_and_gain() {
+        let calibration = Calibration { offset: -1.0, gain: 2.0, points: Vec::new() };
+        assert_eq!(calibration.apply(10.0), 19.0);
+        assert_eq!(Calibration::default().apply(23.125), 23.125);
+    }
+
+    #[test]
+    fn shifts_by_single_point() {
+        let calibration = with_points(vec![point(99.0, 100.0)]);
+        assert_eq!(calibration.apply(20.0), 21.0);
+        assert_eq!(calibration.apply(99.0), 100.0);
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let calibration = with_points(vec![point(1.0, 0.0), point(21.0, 20.0), point(61.0, 100.0)]);
+        assert_eq!(calibration.apply(1.0), 0.0);
+        assert_eq!(calibration.apply(11.0), 10.0);
+        assert_eq!(calibration.apply(21.0), 20.0);
+        assert_eq!(calibration.apply(41.0), 60.0);
+        assert_eq!(calibration.apply(61.0), 100.0);
+    }
+
+    #[test]
+    fn extrapolates_from_outer_points() {
+        let calibration = with_points(vec![point(1.0, 0.0), point(21.0, 20.0), point(61.0, 100.0)]);
+        assert_eq!(calibration.apply(-9.0), -10.0);
+        assert_eq!(calibration.apply(71.0), 120.0);
+    }
+
+    #[test]
+    fn replaces_points() {
+        let mut calibration = Calibration::default();
+        calibration.add_point(point(98.0, 100.0));
+        calibration.add_point(point(1.0, 0.0));
+        calibration.add_point(point(50.0, 50.0));
+        assert_eq!(calibration.points, vec![point(1.0, 0.0), point(50.0, 50.0), point(98.0, 100.0)]);
+
+        // Measuring the same reference again replaces the point
+        calibration.add_point(point(99.0, 100.0));
+        // And so does reading the same temperature again
+        calibration.add_point(point(50.0, 49.0));
+        assert_eq!(calibration.points, vec![point(1.0, 0.0), point(50.0, 49.0), point(99.0, 100.0)]);
+        assert!(calibration.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_calibrations() {
+        assert!(Calibration { gain: 0.0, ..Calibration::default() }.validate().is_err());
+        assert!(Calibration { offset: ::std::f32::NAN, ..Calibration::default() }.validate().is_err());
+        assert!(with_points(vec![point(50.0, 50.0), point(1.0, 0.0)]).validate().is_err());
+        assert!(with_points(vec![point(1.0, 0.0), point(1.0, 2.0)]).validate().is_err());
+
+        let mut calibration = with_points(vec![point(1.0, 0.0)]);
+        calibration.offset = 0.5;
+        assert!(calibration.validate().is_err());
+        calibration.offset = 0.0;
+        calibration.gain = 1.1;
+        assert!(calibration.validate().is_err());
+    }
+}
@@ -1,19 +1,54 @@
 //! Module containing a representation of the DS18B20 onewire temperature sensor.
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use super::sensor::Sensor;
+use super::sensor::{Sensor, SensorError};
+
+/// The directory the kernel lists onewire devices in.
+pub const DEFAULT_DEVICE_DIRECTORY: &str = "/sys/bus/w1/devices";
+
+/// The start of the id of every DS18B20, which has family code 28.
+pub const ID_PREFIX: &str = "28-";
+
+/// The number of times a failed reading is retried, unless set_retries is called.
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// The temperature in w1_slave, in thousandths of a degree Celsius, before the
+/// sensor has made its first conversion after power on.
+const POWER_ON_TEMPERATURE: i32 = 85000;
+
+/// The range of temperatures the DS18B20 can measure, in degrees Celsius.
+const MIN_TEMPERATURE: f32 = -55.0;
+const MAX_TEMPERATURE: f32 = 125.0;
 
 /// Struct representing a DS18B20 onewire temperature sensor.
 pub struct DS18B20 {
     id: String,
+    directory: PathBuf,
+    retries: u32,
 }
 
 impl DS18B20 {
     /// Constructor for DS18B20.
-    /// id is the name of the device, as found in /sys/bus/w1/devices.
+    /// id is the name of the device, as found in DEFAULT_DEVICE_DIRECTORY.
+    /// The ids of the connected devices are returned by discover.
     pub fn new(id: String) -> DS18B20 {
-        // TODO: Scan for devices?
-        DS18B20{id}
+        DS18B20 {
+            id,
+            directory: PathBuf::from(DEFAULT_DEVICE_DIRECTORY),
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// Sets the directory the device is found in, instead of DEFAULT_DEVICE_DIRECTORY.
+    pub fn set_device_directory<P: Into<PathBuf>>(&mut self, directory: P) {
+        self.directory = directory.into();
+    }
+
+    /// Sets the number of times a failed reading is retried before read fails.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
     }
 }
 
@@ -21,15 +56,152 @@ impl DS18B20 {
 /// This implementation uses the Linux kernel module, and its file abstraction.
 /// This is because bit banging onewire from a userspace thread is not able to
 /// meet the timing demands of the bus.
+/// Readings with a wrong CRC, and the value the sensor has at power on, are
+/// rejected and retried.
 impl Sensor for DS18B20 {
-    fn read(&self) -> f32 {
-        fs::read_to_string(format!("/sys/bus/w1/devices/{}/w1_slave", self.id))
-            .expect("Unable to read temperature")
-            .split_whitespace()
-            .filter( |w| w.contains("t=") ) // The temperature is preceded by t=
-            .map( |w| w.trim_left_matches("t=").to_owned() ) // Remove t=
-            .map( |s| s.parse().unwrap() ) // Parse to i32
-            .map( |t: i32| t as f32 / 1000.0 ) // Convert to deg C
-            .nth(0).unwrap() // Take first (and only) element
+    fn read(&self) -> Result<f32, SensorError> {
+        let path = self.directory.join(&self.id).join("w1_slave");
+        let mut attempts = 0;
+        loop {
+            let result = fs::read_to_string(&path)
+                .map_err(SensorError::from)
+                .and_then( |contents| parse_w1_slave(&contents) );
+            match result {
+                Err(ref e) if attempts < self.retries => {
+                    println!("Retrying DS18B20 {}: {}", self.id, e);
+                    attempts += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn get_type(&self) -> String {
+        format!("DS18B20 {}", self.id)
+    }
+}
+
+/// Function returning the ids of every DS18B20 in the given directory, which is
+/// DEFAULT_DEVICE_DIRECTORY unless testing. The ids are sorted.
+pub fn discover<P: AsRef<Path>>(directory: P) -> io::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        if let Ok(id) = entry?.file_name().into_string() {
+            if id.starts_with(ID_PREFIX) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Helper function parsing the contents of w1_slave, which is written by the
+/// kernel as two lines, such as
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+///
+/// The first line ends with YES if the CRC is correct, and NO if it isn't.
+/// The second line ends with the temperature, in thousandths of a degree Celsius.
+fn parse_w1_slave(contents: &str) -> Result<f32, SensorError> {
+    let invalid = || SensorError::Unavailable(format!("Invalid w1_slave: {:?}", contents));
+    let mut lines = contents.lines();
+    let (crc_line, temperature_line) = match (lines.next(), lines.next()) {
+        (Some(crc_line), Some(temperature_line)) => (crc_line, temperature_line),
+        _ => return Err(invalid()),
+    };
+
+    match crc_line.split_whitespace().last() {
+        Some("YES") => {},
+        Some("NO") => return Err(SensorError::CrcFailure),
+        _ => return Err(invalid()),
+    }
+
+    let temperature: i32 = temperature_line.split_whitespace().last()
+        .filter( |w| w.starts_with("t=") )
+        .and_then( |w| w.trim_left_matches("t=").parse().ok() )
+        .ok_or_else(&invalid)?;
+    if temperature == POWER_ON_TEMPERATURE {
+        return Err(SensorError::OutOfRange(temperature as f32 / 1000.0));
+    }
+
+    let temperature = temperature as f32 / 1000.0; // Convert to deg C
+    if temperature < MIN_TEMPERATURE || temperature > MAX_TEMPERATURE {
+        return Err(SensorError::OutOfRange(temperature));
+    }
+    Ok(temperature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Captured from /sys/bus/w1/devices/28-*/w1_slave
+    const VALID: &str = include_str!("../../test_data/w1_slave/valid");
+    const NEGATIVE: &str = include_str!("../../test_data/w1_slave/negative");
+    const CRC_FAILURE: &str = include_str!("../../test_data/w1_slave/crc_failure");
+    const DISCONNECTED: &str = include_str!("../../test_data/w1_slave/disconnected");
+    const POWER_ON: &str = include_str!("../../test_data/w1_slave/power_on");
+    const TRUNCATED: &str = include_str!("../../test_data/w1_slave/truncated");
+
+    #[test]
+    fn parses_temperature() {
+        assert_eq!(parse_w1_slave(VALID), Ok(23.125));
+        assert_eq!(parse_w1_slave(NEGATIVE), Ok(-10.125));
+    }
+
+    #[test]
+    fn rejects_crc_failure() {
+        assert_eq!(parse_w1_slave(CRC_FAILURE), Err(SensorError::CrcFailure));
+        assert_eq!(parse_w1_slave(DISCONNECTED), Err(SensorError::CrcFailure));
+    }
+
+    #[test]
+    fn rejects_power_on_temperature() {
+        assert_eq!(parse_w1_slave(POWER_ON), Err(SensorError::OutOfRange(85.0)));
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        let contents = VALID.replace("t=23125", "t=127937");
+        assert_eq!(parse_w1_slave(&contents), Err(SensorError::OutOfRange(127.937)));
+    }
+
+    #[test]
+    fn discovers_and_reads_devices() {
+        let directory = ::std::env::temp_dir().join(format!("w1-devices-{}", ::std::process::id()));
+        for device in &["28-000009eb40fe", "28-000009eab19f", "10-000802b4a4f1", "w1_bus_master1"] {
+            fs::create_dir_all(directory.join(device)).unwrap();
+        }
+        fs::write(directory.join("28-000009eab19f").join("w1_slave"), VALID).unwrap();
+
+        let ids = discover(&directory).unwrap();
+        assert_eq!(ids, vec!["28-000009eab19f", "28-000009eb40fe"]);
+
+        let mut sensor = DS18B20::new(ids[0].clone());
+        sensor.set_device_directory(&directory);
+        assert_eq!(sensor.read(), Ok(23.125));
+        let mut sensor = DS18B20::new(ids[1].clone());
+        sensor.set_device_directory(&directory);
+        sensor.set_retries(0);
+        match sensor.read() {
+            Err(SensorError::Unavailable(_)) => {},
+            result => panic!("Missing w1_slave gave {:?}", result),
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_contents() {
+        for contents in &[TRUNCATED, "", "\n\n", &VALID.replace("t=23125", "t=abc")] {
+            match parse_w1_slave(contents) {
+                Err(SensorError::Unavailable(_)) => {},
+                result => panic!("{:?} gave {:?}", contents, result),
+            }
+        }
     }
 }
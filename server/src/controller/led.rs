@@ -84,8 +84,10 @@ impl Output for LedOutput {
     }
 
     fn turn_off(&mut self) {
-        for _ in self.state..0 {
-            self.decrease_output();
-        }
+        self.set(0.0);
+    }
+
+    fn get_type(&self) -> String {
+        "LedOutput".to_owned()
     }
 }
@@ -6,7 +6,7 @@ use std::thread;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 
-use super::sensor::Sensor;
+use super::sensor::{Sensor, SensorError};
 use super::output::Output;
 
 const ROOM_TEMPERATURE: f32 = 20.0;
@@ -77,9 +77,13 @@ impl MockTemperatureSensor {
 }
 
 impl Sensor for MockTemperatureSensor {
-    fn read(&self) -> f32 {
-        *self.internal_state.temperature_mutex.lock()
-            .expect("Unable to read temperature_mutex")
+    fn read(&self) -> Result<f32, SensorError> {
+        Ok(*self.internal_state.temperature_mutex.lock()
+           .expect("Unable to read temperature_mutex"))
+    }
+
+    fn get_type(&self) -> String {
+        "MockTemperatureSensor".to_owned()
     }
 }
 
@@ -108,4 +112,8 @@ impl Output for MockOutput {
     fn turn_off(&mut self) {
         *self.0.output_mutex.lock().expect("Unable to lock output mutex") = ROOM_TEMPERATURE;
     }
+
+    fn get_type(&self) -> String {
+        "MockOutput".to_owned()
+    }
 }
@@ -5,81 +5,156 @@ use std::string::ToString;
 use std::fmt::Display;
 use std::fmt;
 use std::thread;
-use std::sync::Mutex;
+use std::sync::{Mutex, PoisonError};
 use std::sync::Arc;
 use std::sync::mpsc::TryRecvError;
-use std::sync::mpsc::channel;
-use std::fs;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::{channel, Sender, Receiver};
 use std::io;
 
+use serde::{Deserialize, Deserializer};
+
 pub mod sensor;
 pub mod output;
 pub mod mock;
 
 pub mod ds18b20;
 pub mod led;
+pub mod calibration;
 
 pub mod pid;
+pub mod status;
+pub mod validation;
 
-use self::sensor::Sensor;
+use self::sensor::{Sensor, FailurePolicy};
 use self::output::Output;
 use self::pid::*;
-use log::{Logger, LogEntry};
-
-/// Function for getting a list of all reference series that are stored.
-pub fn get_list_of_reference_series() -> Vec<String> {
-    // Return a list of all stored ReferanceSeries
-    let mut result = Vec::new();
-    for file in fs::read_dir("references").expect("Unable to read references folder") {
-        result.push(file.expect("Fail while reading log folder")
-                    .file_name()
-                    .into_string()
-                    .expect("Logname not a valid string"));
-    }
-    result
-}
+use self::status::{Progress, Status, ControllerState, saturating_add};
+use self::validation::{Limits, ValidationError};
+use log::{Logger, LogEntry, StepChange, ProcessInfo, Event, EventEntry};
+use storage::{Storage, Name};
 
-/// Function for getting a stored reference series.
-pub fn get_reference_series(name: &String) -> io::Result<String> {
-    fs::read_to_string(format!("references/{}", name))
+/// Function for copying a stored reference series. The copy starts at revision 1.
+/// Fails if a reference series called new_name already exists.
+pub fn copy_reference_series(storage: &Storage, name: &Name, new_name: &Name) -> io::Result<()> {
+    let mut reference_series = storage.get_reference_series(name)?;
+    if storage.get_list_of_reference_series()?.contains(new_name) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  "The reference series already exists"));
+    }
+    reference_series.revision = 1;
+    storage.store_reference_series(new_name, &reference_series)
 }
 
-/// Function for deleting a stored reference series.
-pub fn delete_reference_series(name: String) -> io::Result<()> {
-    fs::remove_file(format!("references/{}", name))
-}
+/// The highest frequency a controller can run on, in Hz, as its period is
+/// counted in whole milliseconds.
+pub const MAX_FREQUENCY: u64 = 1000;
 
-/// Function for storing a new reference series.
-pub fn store_reference_series(name: String, reference_series: ReferenceSeries)
-                              -> io::Result<()> {
-    fs::write(format!("references/{}", name),
-              serde_json::to_string(&reference_series)
-                  .expect("Invalid reference series JSON"))
+/// Struct containing a series of References, and the unit of their temperatures.
+/// The revision is increased every time a stored reference series is replaced,
+/// and is used to detect conflicting updates.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReferenceSeries {
+    pub unit: Unit,
+    pub references: Vec<Reference>,
+    pub revision: u64,
 }
 
-/// Struct containing a series of References.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ReferenceSeries( pub Vec<Reference> );
-
 impl ReferenceSeries {
+    /// Makes a new reference series, with temperatures in degrees Celsius.
     pub fn new(references: Vec<Reference>) -> ReferenceSeries {
-        ReferenceSeries(references)
+        ReferenceSeries {
+            unit: Unit::Celsius,
+            references,
+            revision: 0,
+        }
+    }
+
+    /// Returns this reference series converted to degrees Celsius, which is
+    /// the unit used by the controller.
+    pub fn to_celsius(&self) -> ReferenceSeries {
+        let mut reference_series = ReferenceSeries::new(
+            self.references.iter()
+                .map( |reference| reference.to_celsius(self.unit) )
+                .collect());
+        reference_series.revision = self.revision;
+        reference_series
+    }
+}
+
+/// The formats a reference series can be stored in.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredReferenceSeries {
+    Series {
+        #[serde(default)]
+        unit: Unit,
+        references: Vec<Reference>,
+        #[serde(default)]
+        revision: u64,
+    },
+    /// The old format, a list of references in degrees Celsius.
+    List(Vec<Reference>),
+}
+
+/// Deserializing accepts both the current format, and the old format without
+/// a unit, so reference series stored by earlier versions can still be used.
+impl<'de> Deserialize<'de> for ReferenceSeries {
+    fn deserialize<D>(deserializer: D) -> Result<ReferenceSeries, D::Error>
+    where D: Deserializer<'de>
+    {
+        Ok(match StoredReferenceSeries::deserialize(deserializer)? {
+            StoredReferenceSeries::Series { unit, references, revision } =>
+                ReferenceSeries { unit, references, revision },
+            StoredReferenceSeries::List(references) => ReferenceSeries::new(references),
+        })
     }
 }
 
 impl Display for ReferenceSeries {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut result = String::new();
-        for Reference{duration, temp} in &self.0 {
+        for Reference{duration, temp, ..} in &self.references {
             result.push_str(&duration.to_string());
             result.push_str(": ");
             result.push_str(&temp.to_string());
             result.push_str(", ");
         }
-        write!(f, "{}", result)
+        write!(f, "{}({:?})", result, self.unit)
     }
 }
 
+/// Commands that can be sent to a running process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Stop the process, and turn off the output.
+    Stop,
+    /// Freeze the step timer, while holding the current reference.
+    Pause,
+    /// Continue a paused process.
+    Resume,
+    /// Skip the rest of the current step, and continue with the next one.
+    Next,
+    /// Jump to the step with the given index in the reference series.
+    JumpTo(usize),
+    /// Extend the duration of the current step by the given number of seconds.
+    Extend(u64),
+}
+
+/// The ways a step in the reference series can end.
+enum StepEnd {
+    /// The duration of the step has passed.
+    Finished,
+    /// The rest of the step was skipped.
+    Skipped,
+    /// The process should continue from the step with the given index.
+    JumpTo(usize),
+    /// The process should stop.
+    Stopped,
+    /// The pid thread has stopped.
+    Aborted,
+}
+
 /// Struct representing a controller, with its own input, output and tuning.
 pub struct Controller {
     logger: Arc<Mutex<Option<Logger>>>,
@@ -87,6 +162,10 @@ pub struct Controller {
     output: Arc<Mutex<Box<'static + Output>>>,
     frequency: u64,
     pid_parameters: PidParameters,
+    limits: Limits,
+    failure_policy: FailurePolicy,
+    command_tx: Option<Sender<Command>>,
+    progress: Arc<Mutex<Progress>>,
 }
 
 impl Controller {
@@ -94,7 +173,10 @@ impl Controller {
     /// sensor is the object used to measure the process,
     /// while output is the object used to control the process.
     /// pid_parameters is the parameters used to tune the PID controller.
-    /// Frequency is the frequency the controller is running on.
+    /// Frequency is the frequency the controller is running on, and must be
+    /// between 1 and MAX_FREQUENCY.
+    /// The controller uses the default Limits and FailurePolicy, until
+    /// set_limits and set_failure_policy are called.
     pub fn new<S, O>(sensor: S, output: O, pid_parameters: PidParameters, frequency: u64)
                      -> Controller
     where S: 'static + Sensor + Sync + Send,
@@ -103,15 +185,44 @@ impl Controller {
         Controller {
             pid_parameters,
             frequency,
+            limits: Limits::default(),
+            failure_policy: FailurePolicy::default(),
             sensor: Arc::new(Mutex::new(Box::new(sensor))),
             output: Arc::new(Mutex::new(Box::new(output))),
             logger: Arc::new(Mutex::new(None)),
+            command_tx: None,
+            progress: Arc::new(Mutex::new(Progress::new())),
         }
     }
 
+    /// Sets the limits reference series must be within to be used by this controller.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn get_limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Sets what the controller does when its sensor fails. Used by the next
+    /// process started.
+    pub fn set_failure_policy(&mut self, failure_policy: FailurePolicy) {
+        self.failure_policy = failure_policy;
+    }
+
+    pub fn get_failure_policy(&self) -> &FailurePolicy {
+        &self.failure_policy
+    }
+
+    /// Checks that the given reference series is within the limits of this controller.
+    pub fn validate(&self, reference_series: &ReferenceSeries) -> Result<(), Vec<ValidationError>> {
+        validation::validate(reference_series, &self.limits)
+    }
+
     /// This fuctions starts the controller, which will follow the given reference series.
-    /// At the moment this function will never return an Err value. It can however panic
-    /// if a logfile with the same name (same reference series and date).
+    /// The reference series is converted to degrees Celsius before it is used.
+    /// Returns an Err value if the controller already has a running or paused process,
+    /// or if the reference series is not within the limits of the controller.
     ///
     /// The function spawns three threads:
     /// - A timer thread
@@ -122,21 +233,58 @@ impl Controller {
     /// sends a message to the other two threads with a frequency given by the
     /// Controller object. The reference thread keeps track of how far we are in
     /// the process, and sends an updated reference to the pid thread when there is one.
+    /// The reference thread also receives the Commands sent by stop, pause, resume,
+    /// next_step, jump_to_step and extend_step.
+    /// While ramping, the reference thread sends an interpolated reference every period.
     /// The pid thread takes its references from the reference thread, and calculates
     /// and sets a new output for each tick given by the timer thread.
+    /// If the sensor fails, the pid thread uses the last value read, as given by
+    /// the FailurePolicy of the controller. If the sensor fails for longer, the
+    /// process is faulted.
     /// All these threads works from inside a fourth thread responisble for cleanup
-    /// when the process is finished.
-    pub fn start(&mut self, reference_name: String, reference_series: ReferenceSeries)
-                 -> std::io::Result<()> {
-        let logger = Logger::new(reference_name.clone());
+    /// when the process is finished. The cleanup turns off the output and ends
+    /// the log before the state of the controller changes from Running or Paused.
+    /// The progress of the process is shared with the reference thread, and can
+    /// be read using get_status.
+    /// Returns the name of the log of the process, which is made in storage.
+    /// resource is the name the controller is known by, and is used in the name of the log.
+    pub fn start(&mut self, storage: &Storage, resource: &Name, reference_name: Name, reference_series: ReferenceSeries)
+                 -> std::io::Result<Name> {
+        if let Err(errors) = self.validate(&reference_series) {
+            let errors: Vec<String> = errors.iter().map( |e| e.to_string() ).collect();
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, errors.join(", ")));
+        }
+
+        let log_name;
         {
-            *self.logger.lock().expect("Unable to lock logger") = Some(logger);
+            let progress = &mut *self.progress.lock().unwrap_or_else(PoisonError::into_inner);
+            if progress.is_active() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          "The controller is already running a process"));
+            }
+            let process = ProcessInfo {
+                reference_series: reference_series.clone(),
+                pid_parameters: self.pid_parameters.clone(),
+                frequency: self.frequency,
+                sensor: self.sensor.lock().unwrap_or_else(PoisonError::into_inner).get_type(),
+                output: self.output.lock().unwrap_or_else(PoisonError::into_inner).get_type(),
+            };
+            let logger = Logger::new(storage, &reference_name, resource, process)?;
+            log_name = logger.get_name();
+            *self.logger.lock().unwrap_or_else(PoisonError::into_inner) = Some(logger);
+            progress.start(&reference_series.to_celsius());
         }
 
+        let (command_tx, command_rx) = channel();
+        let cleanup_tx = command_tx.clone();
+        self.command_tx = Some(command_tx);
+
+        let progress = Arc::clone(&self.progress);
         let logger = Arc::clone(&self.logger);
         let output = Arc::clone(&self.output);
         let sensor = Arc::clone(&self.sensor);
         let parameters = self.pid_parameters.clone();
+        let failure_policy = self.failure_policy.clone();
         let period = 1000 / self.frequency;
         let period = Duration::from_millis(period);
 
@@ -161,77 +309,370 @@ impl Controller {
                 }
             });
 
-            // Spawn thread that keeps track of the reference
-            thread::spawn(move || {
+            let progress_ref = Arc::clone(&progress);
+            let logger_ref = Arc::clone(&logger);
+            let sensor_ref = Arc::clone(&sensor);
+
+            // Spawn thread that keeps track of the reference, which returns the
+            // state the process ended in
+            let reference_thread = thread::spawn(move || -> ControllerState {
                 println!("References spawned");
-                for reference in reference_series.0 {
+                let references = reference_series.to_celsius().references;
+                let mut step = 0;
+                while step < references.len() {
+                    let reference = &references[step];
                     println!("new reference: {}", reference.temp);
-                    r_tx.send(reference.temp).expect("r_tx failed"); // This should always work
-                    thread::sleep(Duration::from_secs(reference.duration));
+                    let previous = progress_ref.lock().unwrap_or_else(PoisonError::into_inner)
+                        .get_reference();
+                    // Ramps in the first step starts from the measured value, and
+                    // are skipped if the sensor fails
+                    let from = match previous {
+                        Some(previous) => previous,
+                        None => sensor_ref.lock().unwrap_or_else(PoisonError::into_inner).read()
+                            .unwrap_or(reference.temp),
+                    };
+                    let duration = saturating_add(Duration::from_secs(reference.duration),
+                                                  reference.get_ramp_time(from));
+                    progress_ref.lock().unwrap_or_else(PoisonError::into_inner)
+                        .start_step(step, duration);
+                    log_event(&logger_ref, Event::StepStarted { step, temp: reference.temp });
+                    match wait_for_step(reference, from, period, &r_tx, &command_rx,
+                                        &progress_ref, &logger_ref) {
+                        StepEnd::Finished => step += 1,
+                        StepEnd::Skipped => {
+                            println!("Skipping the rest of step {}", step);
+                            log_step_change(&logger_ref, StepChange::Skip { step });
+                            step += 1;
+                        },
+                        StepEnd::JumpTo(next) => {
+                            println!("Jumping from step {} to {}", step, next);
+                            log_step_change(&logger_ref, StepChange::Jump { from: step, to: next });
+                            step = next;
+                        },
+                        StepEnd::Stopped => {
+                            println!("Process stopped");
+                            return ControllerState::Idle;
+                        },
+                        StepEnd::Aborted => return ControllerState::Faulted, // The pid thread has faulted
+                    }
                 }
+                ControllerState::Finished
             });
 
+            let progress_ref = Arc::clone(&progress);
             let logger_ref = Arc::clone(&logger);
             let output_ref = Arc::clone(&output);
 
-            // Spawn pid thread
-            thread::spawn(move || {
+            // Spawn pid thread, which returns an error message if the process faulted
+            let pid_result = thread::spawn(move || -> Result<(), String> {
                 println!("Pid spawned");
                 let mut pid = Pid::new(&parameters);
-                let mut old_r = match r_rx.recv() {
+                let mut r = match r_rx.recv() {
                     Ok(r) => r,
-                    Err(_) => return, // TODO: Should an empty reference series fail?
+                    Err(_) => return Ok(()), // Empty reference series are rejected by validate
                 };
+                let mut last_input = None;
+                let mut failures = 0;
 
                 loop {
                     let _ = timer_rx.recv().expect("Timer thread has died prematurely");
-                    let r = match r_rx.try_recv() {
-                        Ok(reference) => {
-                            println!("new referenc received: {}", reference);
-                            old_r = reference;
-                            reference
-                        },
-                        Err(e) => match e {
-                            TryRecvError::Empty => old_r,
-                            TryRecvError::Disconnected => return,
+                    // Use the newest reference, as several may be received while ramping
+                    loop {
+                        match r_rx.try_recv() {
+                            Ok(reference) => r = reference,
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => return Ok(()),
                         }
+                    }
+
+                    let result = sensor.lock().unwrap_or_else(PoisonError::into_inner).read();
+                    let y = match result {
+                        Ok(y) => {
+                            failures = 0;
+                            last_input = Some(y);
+                            y
+                        },
+                        Err(e) => {
+                            failures += 1;
+                            println!("Unable to read sensor: {}", e);
+                            if failures == 1 {
+                                log_event(&logger_ref, Event::SensorFailed { message: e.to_string() });
+                            }
+                            if failures > failure_policy.hold_ticks {
+                                return Err(format!("The sensor failed {} times in a row: {}",
+                                                   failures, e));
+                            }
+                            match last_input {
+                                Some(y) => y, // Hold the last value
+                                None => continue, // Nothing to hold yet
+                            }
+                        },
                     };
 
                     {
-                        let y = sensor.lock().expect("Unable to lock sensor").read();
-                        let u = pid.pid(y, r as f32);
-                        output_ref.lock().expect("Unable to lock output").set(u);
+                        progress_ref.lock().unwrap_or_else(PoisonError::into_inner).set_input(y);
+                        let u = pid.pid(y, r);
+                        output_ref.lock().unwrap_or_else(PoisonError::into_inner).set(u);
 
-                        let logger = &mut *logger_ref.lock().expect("Unable to lock logger");
-                        logger.as_mut().expect("Unable to take logger as mut")
-                            .add_entry(r as f32, y, u);
+                        // A failed write must not stop the control of the process
+                        let logger = &mut *logger_ref.lock().unwrap_or_else(PoisonError::into_inner);
+                        let logger = logger.as_mut().expect("Unable to take logger as mut");
+                        if let Err(e) = logger.add_entry(r, y, u) {
+                            println!("Unable to write log {}: {}", logger.get_name(), e);
+                        }
                     }
                 };
-            }).join().expect("Unable to join pid thread"); // The thread should not panic, unless something has gone horribly wrong
+            }).join();
+
+            // The output is turned off first, whatever happened to the other threads
+            output.lock().unwrap_or_else(PoisonError::into_inner).turn_off();
+
+            // The pid thread should not panic, unless something has gone horribly wrong
+            let fault = match pid_result {
+                Ok(Ok(())) => None,
+                Ok(Err(message)) => Some(message),
+                Err(_) => Some("The pid thread panicked".to_owned()),
+            };
+            if fault.is_some() {
+                let _ = cleanup_tx.send(Command::Stop); // Stop the reference thread
+            }
+            let (state, event) = match (fault, reference_thread.join()) {
+                (Some(message), _) => (ControllerState::Faulted, Event::Faulted { message }),
+                (None, Ok(ControllerState::Finished)) => (ControllerState::Finished, Event::Finished),
+                (None, Ok(_)) => (ControllerState::Idle, Event::Stopped),
+                (None, Err(_)) => (ControllerState::Faulted, Event::Faulted {
+                    message: "The reference thread panicked".to_owned()
+                }),
+            };
+            if let Event::Faulted { ref message } = event {
+                println!("Controller faulted: {}", message);
+            }
+            log_event(&logger, event);
 
             println!("Controller finished");
-            output.lock().expect("Unable to lock output").turn_off();
-            *logger.lock().expect("Unable to lock logger") = None;
+            *logger.lock().unwrap_or_else(PoisonError::into_inner) = None;
+            // The process ends last, so a new process can not be started
+            // before the output and the logger of this one are released
+            progress.lock().unwrap_or_else(PoisonError::into_inner).finish(state);
         });
 
-        Ok(())
+        Ok(log_name)
+    }
+
+    /// Stops the running process. The output is turned off when the controller
+    /// has finished. Fails if there is no running process.
+    pub fn stop(&self) -> io::Result<()> {
+        self.send_command(Command::Stop)
+    }
+
+    /// Pauses the running process. The current reference is held, but the
+    /// step timer is frozen until the process is resumed.
+    /// Fails if there is no running process.
+    pub fn pause(&self) -> io::Result<()> {
+        self.send_command(Command::Pause)
+    }
+
+    /// Resumes a paused process. Fails if there is no running process.
+    pub fn resume(&self) -> io::Result<()> {
+        self.send_command(Command::Resume)
+    }
+
+    /// Skips the rest of the current step, and continues with the next one.
+    /// If the current step is the last one, the process finishes.
+    /// Fails if there is no running process.
+    pub fn next_step(&self) -> io::Result<()> {
+        self.send_command(Command::Next)
+    }
+
+    /// Jumps to the step with the given index in the reference series.
+    /// Fails if there is no running process, or the reference series has no such step.
+    pub fn jump_to_step(&self, step: usize) -> io::Result<()> {
+        let (active, number_of_steps) = {
+            let progress = self.progress.lock().unwrap_or_else(PoisonError::into_inner);
+            (progress.is_active(), progress.get_number_of_steps())
+        };
+        if !active {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "There is no running process"));
+        }
+        if step >= number_of_steps {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The reference series has no such step"));
+        }
+        self.send_command(Command::JumpTo(step))
+    }
+
+    /// Extends the duration of the current step by the given number of seconds.
+    /// Fails if there is no running process.
+    pub fn extend_step(&self, seconds: u64) -> io::Result<()> {
+        self.send_command(Command::Extend(seconds))
+    }
+
+    /// Returns the state of the controller, and the progress of the current
+    /// or last process.
+    pub fn get_status(&self) -> Status {
+        self.progress.lock().unwrap_or_else(PoisonError::into_inner).get_status()
+    }
+
+    /// Helper function for sending a Command to the reference thread.
+    fn send_command(&self, command: Command) -> io::Result<()> {
+        let not_running = || io::Error::new(io::ErrorKind::NotConnected,
+                                            "There is no running process");
+        match self.command_tx {
+            Some(ref command_tx) => command_tx.send(command).map_err(|_| not_running()),
+            None => Err(not_running()),
+        }
     }
 
     /// Function for getting the last saved log entry. Note if there are no process
     /// running there are no log entry stored.
     pub fn get_last_log_entry(&self) -> Option<LogEntry> {
-        match *self.logger.lock().expect("Unable to lock logger") {
+        match *self.logger.lock().unwrap_or_else(PoisonError::into_inner) {
             Some(ref logger) => logger.get_last_entry(),
             None => None
         }
     }
 
-    /// Function for getting the name of the current process/the reference series
-    /// used by the current process.
-    pub fn get_name_of_current_process(&self) -> Option<String> {
-        match *self.logger.lock().expect("Unable to lock logger") {
+    /// Function for getting the name of the current process, which is the name
+    /// of the log used by the current process.
+    pub fn get_name_of_current_process(&self) -> Option<Name> {
+        match *self.logger.lock().unwrap_or_else(PoisonError::into_inner) {
             Some(ref logger) => Some(logger.get_name()),
             None => None
         }
     }
+
+    /// Records an event in the log called log_name, if it is the log of the
+    /// current process. Returns false if it is not.
+    pub fn add_event(&self, log_name: &Name, event: &EventEntry) -> io::Result<bool> {
+        match *self.logger.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(ref mut logger) => if logger.get_name() == *log_name {
+                logger.add_event(event.clone())?;
+                Ok(true)
+            } else {
+                Ok(false)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+/// Helper function used by the reference thread to wait for a step to finish.
+/// Handles the Commands received while waiting, and updates the progress.
+/// While ramping from the setpoint given by from, a new setpoint is sent to the
+/// pid thread every period. If the step should hold until the reference is reached,
+/// the measured value is checked every period until the step timer can continue.
+/// Returns how the step ended.
+fn wait_for_step(reference: &Reference,
+                 from: f32,
+                 period: Duration,
+                 r_tx: &Sender<f32>,
+                 command_rx: &Receiver<Command>,
+                 progress: &Mutex<Progress>,
+                 logger: &Mutex<Option<Logger>>)
+                 -> StepEnd
+{
+    let ramp_time = reference.get_ramp_time(from);
+    let mut reached = reference.hold.is_none();
+    loop {
+        let (paused, waiting, elapsed, remaining, step, input, time_waiting) = {
+            let progress = progress.lock().unwrap_or_else(PoisonError::into_inner);
+            (progress.get_state() == ControllerState::Paused,
+             progress.is_waiting(),
+             progress.get_step_elapsed(),
+             progress.get_step_remaining(),
+             progress.get_step().unwrap_or(0),
+             progress.get_input(),
+             progress.get_time_waiting())
+        };
+        let ramping = elapsed < ramp_time;
+
+        let setpoint = reference.get_setpoint(from, elapsed);
+        if r_tx.send(setpoint).is_err() {
+            return StepEnd::Aborted;
+        }
+        progress.lock().unwrap_or_else(PoisonError::into_inner).set_reference(setpoint);
+
+        if !ramping && !reached {
+            if !waiting {
+                progress.lock().unwrap_or_else(PoisonError::into_inner).wait_for_reference();
+                continue;
+            }
+            if let Some(ref hold) = reference.hold {
+                let is_reached = match input {
+                    Some(input) => (input - reference.temp).abs() <= hold.band,
+                    None => false,
+                };
+                let timed_out = match hold.timeout {
+                    Some(timeout) => time_waiting >= Duration::from_secs(timeout),
+                    None => false,
+                };
+                if is_reached || timed_out {
+                    progress.lock().unwrap_or_else(PoisonError::into_inner).reach_reference();
+                    let change = if is_reached {
+                        println!("Reference reached in step {}", step);
+                        StepChange::Reached { step, seconds: time_waiting.as_secs() }
+                    } else {
+                        println!("Timed out waiting for reference in step {}", step);
+                        StepChange::HoldTimeout { step }
+                    };
+                    log_step_change(logger, change);
+                    reached = true;
+                    continue;
+                }
+            }
+        }
+
+        let command = if waiting || (ramping && !paused) {
+            command_rx.recv_timeout(period) // Check the measured value or the ramp again
+        } else if paused {
+            command_rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            command_rx.recv_timeout(remaining)
+        };
+        match command {
+            Err(RecvTimeoutError::Timeout) => if !waiting && !ramping {
+                return StepEnd::Finished;
+            },
+            Err(RecvTimeoutError::Disconnected) => return StepEnd::Stopped,
+            Ok(Command::Stop) => return StepEnd::Stopped,
+            Ok(Command::Pause) => {
+                println!("Process paused");
+                progress.lock().unwrap_or_else(PoisonError::into_inner).pause();
+                log_event(logger, Event::Paused);
+            },
+            Ok(Command::Resume) => {
+                println!("Process resumed");
+                progress.lock().unwrap_or_else(PoisonError::into_inner).resume();
+                log_event(logger, Event::Resumed);
+            },
+            Ok(Command::Next) => return StepEnd::Skipped,
+            Ok(Command::JumpTo(next)) => return StepEnd::JumpTo(next),
+            Ok(Command::Extend(seconds)) => {
+                println!("Extending step {} by {} s", step, seconds);
+                progress.lock().unwrap_or_else(PoisonError::into_inner)
+                    .extend_step(Duration::from_secs(seconds));
+                log_step_change(logger, StepChange::Extend { step, seconds });
+            },
+        }
+    }
+}
+
+/// Helper function for recording an Event in the log of the current process.
+/// The event is recorded even if a thread panicked while holding the logger,
+/// so a fault can be logged.
+fn log_event(logger: &Mutex<Option<Logger>>, event: Event) {
+    if let Some(ref mut logger) = *logger.lock().unwrap_or_else(PoisonError::into_inner) {
+        if let Err(e) = logger.add_event(EventEntry::new(event)) {
+            println!("Unable to write log {}: {}", logger.get_name(), e);
+        }
+    }
+}
+
+/// Helper function for recording a StepChange in the log of the current process.
+fn log_step_change(logger: &Mutex<Option<Logger>>, change: StepChange) {
+    if let Some(ref mut logger) = *logger.lock().unwrap_or_else(PoisonError::into_inner) {
+        if let Err(e) = logger.add_step_change(change) {
+            println!("Unable to write log {}: {}", logger.get_name(), e);
+        }
+    }
 }
@@ -11,4 +11,23 @@ pub trait Output : Send + Sync {
 
     /// Function used to turn off the output when it is no more used.
     fn turn_off(&mut self);
+
+    /// Function returning a description of the type of output. Used to
+    /// identify the output in logs.
+    fn get_type(&self) -> String;
+}
+
+/// A boxed Output, such as an output chosen at runtime, is also an Output.
+impl<O: ?Sized + Output> Output for Box<O> {
+    fn set(&mut self, output: f32) {
+        (**self).set(output)
+    }
+
+    fn turn_off(&mut self) {
+        (**self).turn_off()
+    }
+
+    fn get_type(&self) -> String {
+        (**self).get_type()
+    }
 }
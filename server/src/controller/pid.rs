@@ -2,23 +2,145 @@
 
 use std::default::Default;
 use std::f32;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
 
 /// Struct representing a reference, and how long that reference should be held.
+/// If ramp is given, the setpoint moves linearly from the previous reference to
+/// this one before the duration starts counting down.
+/// If hold is given, the duration does not start counting down before the
+/// measured value has reached the reference.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Reference {
     pub duration: u64,
-    pub temp: u32
+    pub temp: f32,
+    #[serde(default)]
+    pub hold: Option<Hold>,
+    #[serde(default)]
+    pub ramp: Option<Ramp>,
+}
+
+impl Reference {
+    /// Returns how long it takes to ramp from the given setpoint to this reference.
+    pub fn get_ramp_time(&self, from: f32) -> Duration {
+        match self.ramp {
+            Some(Ramp::Rate(rate)) if rate > 0.0 => {
+                let minutes = (self.temp - from).abs() / rate;
+                Duration::from_millis((minutes * 60_000.0) as u64)
+            },
+            Some(Ramp::Duration(seconds)) => Duration::from_secs(seconds),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// Returns the setpoint the given time after the step started, when
+    /// ramping from the given setpoint.
+    pub fn get_setpoint(&self, from: f32, elapsed: Duration) -> f32 {
+        let target = self.temp;
+        let ramp_time = as_millis(self.get_ramp_time(from));
+        let elapsed = as_millis(elapsed);
+        if elapsed >= ramp_time {
+            target
+        } else {
+            from + (target - from) * elapsed / ramp_time
+        }
+    }
+
+    /// Returns this reference converted from the given unit to degrees Celsius.
+    pub fn to_celsius(&self, unit: Unit) -> Reference {
+        let mut reference = self.clone();
+        reference.temp = unit.to_celsius(self.temp);
+        if let Some(ref mut hold) = reference.hold {
+            hold.band = unit.difference_to_celsius(hold.band);
+        }
+        if let Some(Ramp::Rate(ref mut rate)) = reference.ramp {
+            *rate = unit.difference_to_celsius(*rate);
+        }
+        reference
+    }
+}
+
+/// Helper function converting a Duration to milliseconds.
+fn as_millis(duration: Duration) -> f32 {
+    duration.as_secs() as f32 * 1000.0 + duration.subsec_millis() as f32
+}
+
+/// The temperature unit used by a reference series.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl Unit {
+    /// Converts a temperature in this unit to degrees Celsius.
+    pub fn to_celsius(&self, temp: f32) -> f32 {
+        match *self {
+            Unit::Celsius => temp,
+            Unit::Fahrenheit => (temp - 32.0) * 5.0 / 9.0,
+        }
+    }
+
+    /// Converts a temperature difference (or rate) in this unit to degrees Celsius.
+    pub fn difference_to_celsius(&self, difference: f32) -> f32 {
+        match *self {
+            Unit::Celsius => difference,
+            Unit::Fahrenheit => difference * 5.0 / 9.0,
+        }
+    }
+}
+
+/// Unit::Celsius
+impl Default for Unit {
+    fn default() -> Unit {
+        Unit::Celsius
+    }
+}
+
+/// How the setpoint moves from the previous reference to a new reference.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Ramp {
+    /// Change the setpoint with the given rate, in degrees per minute.
+    Rate(f32),
+    /// Change the setpoint linearly over the given number of seconds.
+    Duration(u64),
+}
+
+/// Struct describing when the reference of a "hold until reached" step is
+/// considered reached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hold {
+    /// The reference is reached when the measured value is within this distance
+    /// of the reference.
+    pub band: f32,
+    /// Number of seconds to wait for the reference to be reached, before the
+    /// duration starts counting down anyway. None means wait forever.
+    #[serde(default)]
+    pub timeout: Option<u64>,
 }
 
 /// Struct containing tuning parameters for the PID controller.
-#[derive(Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PidParameters {
     kp: f32,
     ki: f32,
     kd: f32,
+    /// Infinity is encoded as null in JSON, and is used if max_integrator is left out.
+    #[serde(deserialize_with = "deserialize_max_integrator", default = "no_max_integrator")]
     max_integrator: f32,
 }
 
+/// Helper function returning the max_integrator used when none is given.
+fn no_max_integrator() -> f32 {
+    f32::INFINITY
+}
+
+/// Helper function reading an infinite max_integrator back from JSON.
+fn deserialize_max_integrator<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    Ok(Option::<f32>::deserialize(deserializer)?.unwrap_or(f32::INFINITY))
+}
+
 impl PidParameters {
     /// Constructor for the PidParameters struct. max_integrator is the maximum value that
     /// the integrator can have. This is used to proved anti-windup.
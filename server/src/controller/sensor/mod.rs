@@ -1,9 +1,80 @@
 //! Module containig types and functionality for reading sensor values of the temperature sensors
 
+use std::fmt;
+use std::fmt::Display;
+use std::io;
 use std::marker::{Send, Sync};
 
 /// Trait that must be implemented by types that are used as inputs by the controllers.
 pub trait Sensor : Send + Sync {
-    /// Function for reading from a Sensor
-    fn read(&self) -> f32;
+    /// Function for reading from a Sensor. Fails if the sensor could not give
+    /// a valid temperature.
+    fn read(&self) -> Result<f32, SensorError>;
+
+    /// Function returning a description of the type of sensor, such as
+    /// "DS18B20 28-000009eab19f". Used to identify the sensor in logs.
+    fn get_type(&self) -> String;
+}
+
+/// A boxed Sensor, such as a sensor chosen at runtime, is also a Sensor.
+impl<S: ?Sized + Sensor> Sensor for Box<S> {
+    fn read(&self) -> Result<f32, SensorError> {
+        (**self).read()
+    }
+
+    fn get_type(&self) -> String {
+        (**self).get_type()
+    }
+}
+
+/// The reasons a sensor can fail to give a temperature.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum SensorError {
+    /// The sensor could not be reached, such as when it is unplugged. The
+    /// message tells why.
+    Unavailable(String),
+    /// The value was corrupted on its way from the sensor.
+    CrcFailure,
+    /// The sensor gave a value that can not be a real temperature.
+    OutOfRange(f32),
+    /// The sensor did not answer in time.
+    Timeout,
+}
+
+impl Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SensorError::Unavailable(ref message) =>
+                write!(f, "The sensor is unavailable: {}", message),
+            SensorError::CrcFailure => write!(f, "The CRC of the reading is wrong"),
+            SensorError::OutOfRange(value) =>
+                write!(f, "The reading {} is out of range", value),
+            SensorError::Timeout => write!(f, "The sensor did not answer in time"),
+        }
+    }
+}
+
+impl From<io::Error> for SensorError {
+    fn from(error: io::Error) -> SensorError {
+        match error.kind() {
+            io::ErrorKind::TimedOut => SensorError::Timeout,
+            _ => SensorError::Unavailable(error.to_string()),
+        }
+    }
+}
+
+/// What a Controller does when its sensor fails. The last temperature read is
+/// used for up to hold_ticks ticks in a row. If the sensor fails for longer,
+/// the process is faulted and the output is turned off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FailurePolicy {
+    pub hold_ticks: u32,
+}
+
+/// hold_ticks = 10
+impl Default for FailurePolicy {
+    fn default() -> FailurePolicy {
+        FailurePolicy { hold_ticks: 10 }
+    }
 }
@@ -0,0 +1,380 @@
+//! Module containing types used to keep track of the state of a controller.
+
+use std::time::{Duration, Instant};
+
+use super::ReferenceSeries;
+
+/// The states a Controller can be in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ControllerState {
+    /// No process has been started, or the last process was stopped.
+    Idle,
+    Running,
+    Paused,
+    /// The last process has followed all of its references.
+    Finished,
+    /// The last process was stopped due to an error.
+    Faulted,
+}
+
+/// Snapshot of the progress of a controller. All times are in seconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub state: ControllerState,
+    /// Index of the current step in the reference series. None if no process
+    /// has been started.
+    pub step: Option<usize>,
+    /// True while a "hold until reached" step waits for the reference to be reached.
+    pub waiting: bool,
+    /// The current setpoint. Changes every tick while ramping.
+    pub reference: Option<f32>,
+    pub step_elapsed: u64,
+    pub step_remaining: u64,
+    pub total_elapsed: u64,
+    pub total_remaining: u64,
+}
+
+/// Struct keeping track of how far a process has come. It is shared between
+/// the Controller and its reference thread.
+#[derive(Debug)]
+pub struct Progress {
+    state: ControllerState,
+    /// The duration of each step in the reference series.
+    durations: Vec<Duration>,
+    step: Option<usize>,
+    /// True while the step timer waits for the reference to be reached.
+    waiting: bool,
+    /// When the step timer started waiting for the reference to be reached.
+    waiting_since: Option<Instant>,
+    /// The current setpoint.
+    reference: Option<f32>,
+    /// The last measured value.
+    input: Option<f32>,
+    /// Time spent in previous steps.
+    previous_elapsed: Duration,
+    /// Time spent in the current step, not counting the time since running_since.
+    step_elapsed: Duration,
+    /// When the step timer was last started. None when the timer is frozen.
+    running_since: Option<Instant>,
+}
+
+impl Progress {
+    /// Makes a new Progress, in the Idle state.
+    pub fn new() -> Progress {
+        Progress {
+            state: ControllerState::Idle,
+            durations: Vec::new(),
+            step: None,
+            waiting: false,
+            waiting_since: None,
+            reference: None,
+            input: None,
+            previous_elapsed: Duration::from_secs(0),
+            step_elapsed: Duration::from_secs(0),
+            running_since: None,
+        }
+    }
+
+    pub fn get_state(&self) -> ControllerState {
+        self.state
+    }
+
+    /// Returns the index of the current step, if a process has been started.
+    pub fn get_step(&self) -> Option<usize> {
+        self.step
+    }
+
+    /// Returns true while the step timer waits for the reference to be reached.
+    pub fn is_waiting(&self) -> bool {
+        self.waiting
+    }
+
+    /// Returns the time since the step timer started waiting for the reference
+    /// to be reached, including time spent paused.
+    pub fn get_time_waiting(&self) -> Duration {
+        match self.waiting_since {
+            Some(since) => since.elapsed(),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Returns the current setpoint.
+    pub fn get_reference(&self) -> Option<f32> {
+        self.reference
+    }
+
+    /// Stores the current setpoint.
+    pub fn set_reference(&mut self, reference: f32) {
+        self.reference = Some(reference);
+    }
+
+    /// Returns the last measured value.
+    pub fn get_input(&self) -> Option<f32> {
+        self.input
+    }
+
+    /// Stores the last measured value.
+    pub fn set_input(&mut self, input: f32) {
+        self.input = Some(input);
+    }
+
+    /// Returns the number of steps in the reference series of the current process.
+    pub fn get_number_of_steps(&self) -> usize {
+        self.durations.len()
+    }
+
+    /// Returns true if there is a running or paused process.
+    pub fn is_active(&self) -> bool {
+        self.state == ControllerState::Running || self.state == ControllerState::Paused
+    }
+
+    /// Resets the progress for a new process following the given reference series.
+    /// The durations of ramps are estimated from the previous reference, and
+    /// are updated by start_step.
+    pub fn start(&mut self, reference_series: &ReferenceSeries) {
+        *self = Progress::new();
+        let mut previous = None;
+        for reference in &reference_series.references {
+            let ramp_time = reference.get_ramp_time(previous.unwrap_or(reference.temp));
+            self.durations.push(saturating_add(Duration::from_secs(reference.duration), ramp_time));
+            previous = Some(reference.temp);
+        }
+        self.state = ControllerState::Running;
+    }
+
+    /// Marks the start of the given step, which will last for the given
+    /// duration. The step timer starts immediately, unless the process is paused.
+    pub fn start_step(&mut self, step: usize, duration: Duration) {
+        self.previous_elapsed = saturating_add(self.previous_elapsed, self.get_step_elapsed());
+        self.step_elapsed = Duration::from_secs(0);
+        self.step = Some(step);
+        self.durations[step] = duration;
+        self.waiting = false;
+        self.waiting_since = None;
+        self.running_since = match self.state {
+            ControllerState::Running => Some(Instant::now()),
+            _ => None,
+        };
+    }
+
+    /// Freezes the step timer until reach_reference is called.
+    pub fn wait_for_reference(&mut self) {
+        if !self.waiting {
+            self.step_elapsed = self.get_step_elapsed();
+            self.running_since = None;
+            self.waiting = true;
+            self.waiting_since = Some(Instant::now());
+        }
+    }
+
+    /// Marks that the reference of the current step has been reached, and
+    /// starts the step timer unless the process is paused.
+    pub fn reach_reference(&mut self) {
+        if self.waiting {
+            self.waiting = false;
+            if self.state == ControllerState::Running {
+                self.running_since = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Extends the duration of the current step.
+    pub fn extend_step(&mut self, extension: Duration) {
+        if let Some(step) = self.step {
+            self.durations[step] = saturating_add(self.durations[step], extension);
+        }
+    }
+
+    /// Freezes the step timer. Has no effect unless the process is running.
+    pub fn pause(&mut self) {
+        if self.state == ControllerState::Running {
+            self.step_elapsed = self.get_step_elapsed();
+            self.running_since = None;
+            self.state = ControllerState::Paused;
+        }
+    }
+
+    /// Restarts the step timer. Has no effect unless the process is paused.
+    /// If the step is waiting for the reference to be reached, the timer is
+    /// started by reach_reference instead.
+    pub fn resume(&mut self) {
+        if self.state == ControllerState::Paused {
+            if !self.waiting {
+                self.running_since = Some(Instant::now());
+            }
+            self.state = ControllerState::Running;
+        }
+    }
+
+    /// Ends the process with the given state. Has no effect if the process
+    /// has already ended, so the first reason given is kept.
+    pub fn finish(&mut self, state: ControllerState) {
+        if self.is_active() {
+            self.step_elapsed = self.get_step_elapsed();
+            self.running_since = None;
+            self.state = state;
+        }
+    }
+
+    /// Returns the time spent in the current step.
+    pub fn get_step_elapsed(&self) -> Duration {
+        match self.running_since {
+            Some(since) => self.step_elapsed + since.elapsed(),
+            None => self.step_elapsed,
+        }
+    }
+
+    /// Returns the time left of the current step.
+    pub fn get_step_remaining(&self) -> Duration {
+        match self.step {
+            Some(step) => self.durations[step].checked_sub(self.get_step_elapsed())
+                .unwrap_or(Duration::from_secs(0)),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Returns a snapshot of the progress.
+    pub fn get_status(&self) -> Status {
+        let step_elapsed = self.get_step_elapsed();
+        let (step_remaining, total_remaining) = match self.step {
+            Some(step) if self.is_active() => {
+                let step_remaining = self.get_step_remaining();
+                let later_steps = self.durations.iter().skip(step + 1)
+                    .fold(Duration::from_secs(0), |sum, duration| saturating_add(sum, *duration));
+                (step_remaining, saturating_add(step_remaining, later_steps))
+            },
+            _ => (Duration::from_secs(0), Duration::from_secs(0)),
+        };
+
+        Status {
+            state: self.state,
+            step: self.step,
+            waiting: self.waiting && self.is_active(),
+            reference: self.reference,
+            step_elapsed: step_elapsed.as_secs(),
+            step_remaining: step_remaining.as_secs(),
+            total_elapsed: saturating_add(self.previous_elapsed, step_elapsed).as_secs(),
+            total_remaining: total_remaining.as_secs(),
+        }
+    }
+}
+
+/// Helper function adding two durations, giving the longest duration that can
+/// be represented instead of overflowing.
+pub fn saturating_add(a: Duration, b: Duration) -> Duration {
+    a.checked_add(b).unwrap_or(Duration::from_secs(u64::max_value()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use controller::pid::Reference;
+
+    fn start_process() -> Progress {
+        let reference = |duration, temp| Reference { duration, temp, hold: None, ramp: None };
+        let reference_series = ReferenceSeries::new(vec![reference(60, 66.0), reference(600, 78.0)]);
+        let mut progress = Progress::new();
+        progress.start(&reference_series);
+        progress.start_step(0, Duration::from_secs(60));
+        progress
+    }
+
+    fn sleep() {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn pause_freezes_step_timer() {
+        let mut progress = start_process();
+        sleep();
+        progress.pause();
+        assert_eq!(progress.get_state(), ControllerState::Paused);
+        let elapsed = progress.get_step_elapsed();
+        assert!(elapsed >= Duration::from_millis(20));
+        sleep();
+        assert_eq!(progress.get_step_elapsed(), elapsed);
+
+        progress.resume();
+        assert_eq!(progress.get_state(), ControllerState::Running);
+        sleep();
+        assert!(progress.get_step_elapsed() > elapsed);
+    }
+
+    #[test]
+    fn waiting_freezes_step_timer_until_reached() {
+        let mut progress = start_process();
+        progress.wait_for_reference();
+        assert!(progress.is_waiting());
+        assert!(progress.get_status().waiting);
+        let elapsed = progress.get_step_elapsed();
+
+        // Resuming while waiting does not start the step timer
+        progress.pause();
+        progress.resume();
+        sleep();
+        assert_eq!(progress.get_step_elapsed(), elapsed);
+        assert!(progress.get_time_waiting() >= Duration::from_millis(20));
+
+        progress.reach_reference();
+        assert!(!progress.is_waiting());
+        sleep();
+        assert!(progress.get_step_elapsed() > elapsed);
+    }
+
+    #[test]
+    fn reaching_reference_while_paused_keeps_timer_frozen() {
+        let mut progress = start_process();
+        progress.wait_for_reference();
+        progress.pause();
+        progress.reach_reference();
+        let elapsed = progress.get_step_elapsed();
+        sleep();
+        assert_eq!(progress.get_step_elapsed(), elapsed);
+        progress.resume();
+        sleep();
+        assert!(progress.get_step_elapsed() > elapsed);
+    }
+
+    #[test]
+    fn finish_keeps_first_reason() {
+        let mut progress = start_process();
+        progress.wait_for_reference();
+        progress.finish(ControllerState::Faulted);
+        progress.finish(ControllerState::Finished);
+        progress.resume();
+        let status = progress.get_status();
+        assert_eq!(status.state, ControllerState::Faulted);
+        assert!(!status.waiting);
+        assert_eq!(status.step_remaining, 0);
+        assert_eq!(status.total_remaining, 0);
+    }
+
+    #[test]
+    fn extend_lengthens_current_step() {
+        let mut progress = start_process();
+        progress.pause();
+        let status = progress.get_status();
+        assert_eq!(status.total_remaining, status.step_remaining + 600);
+
+        progress.extend_step(Duration::from_secs(120));
+        let extended = progress.get_status();
+        assert_eq!(extended.step_remaining, status.step_remaining + 120);
+        assert_eq!(extended.total_remaining, status.total_remaining + 120);
+
+        progress.extend_step(Duration::from_secs(u64::max_value()));
+        assert_eq!(progress.get_status().total_remaining, u64::max_value());
+    }
+
+    #[test]
+    fn counts_time_of_previous_steps() {
+        let mut progress = start_process();
+        sleep();
+        progress.start_step(1, Duration::from_secs(600));
+        progress.pause();
+        let status = progress.get_status();
+        assert_eq!(status.step, Some(1));
+        assert!(progress.previous_elapsed >= Duration::from_millis(20));
+        assert_eq!(status.total_remaining, status.step_remaining);
+    }
+}
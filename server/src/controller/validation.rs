@@ -0,0 +1,273 @@
+//! Module containing the checks a reference series must pass before it is
+//! stored or used by a controller.
+
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+use super::ReferenceSeries;
+use super::pid::Ramp;
+
+/// The limits a reference series must be within. Temperatures are in degrees
+/// Celsius, and durations in seconds. Limits that are left out when decoded
+/// are taken from the default Limits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub min_temp: f32,
+    pub max_temp: f32,
+    /// Minimum duration of a step. Steps with a ramp or hold may be shorter.
+    pub min_duration: u64,
+    pub max_duration: u64,
+    /// Maximum duration of the whole reference series, including ramps.
+    pub max_total_duration: u64,
+}
+
+/// min_temp = 0, max_temp = 110, min_duration = 1, max_duration = 14 days,
+/// max_total_duration = 60 days
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            min_temp: 0.0,
+            max_temp: 110.0,
+            min_duration: 1,
+            max_duration: 14 * 24 * 60 * 60,
+            max_total_duration: 60 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// A problem found in a reference series. Steps are indices into the reference
+/// series, and temperatures are in degrees Celsius.
+#[derive(Debug, Clone, Serialize)]
+pub enum ValidationError {
+    /// The reference series has no references.
+    Empty,
+    DurationTooShort { step: usize, duration: u64, min: u64 },
+    DurationTooLong { step: usize, duration: u64, max: u64 },
+    TemperatureTooLow { step: usize, temp: f32, min: f32 },
+    TemperatureTooHigh { step: usize, temp: f32, max: f32 },
+    /// The band of a hold step is not positive.
+    InvalidHoldBand { step: usize, band: f32 },
+    /// The rate of a ramp is not positive.
+    InvalidRampRate { step: usize, rate: f32 },
+    /// The ramp of a step may take longer than the maximum duration of a step.
+    RampTooLong { step: usize, duration: u64, max: u64 },
+    /// The timeout of a hold step is longer than the maximum duration of a step.
+    HoldTimeoutTooLong { step: usize, timeout: u64, max: u64 },
+    TotalDurationTooLong { duration: u64, max: u64 },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::Empty =>
+                write!(f, "The reference series is empty"),
+            ValidationError::DurationTooShort { step, duration, min } =>
+                write!(f, "Step {}: duration {} s is shorter than {} s", step, duration, min),
+            ValidationError::DurationTooLong { step, duration, max } =>
+                write!(f, "Step {}: duration {} s is longer than {} s", step, duration, max),
+            ValidationError::TemperatureTooLow { step, temp, min } =>
+                write!(f, "Step {}: temperature {} is below {}", step, temp, min),
+            ValidationError::TemperatureTooHigh { step, temp, max } =>
+                write!(f, "Step {}: temperature {} is above {}", step, temp, max),
+            ValidationError::InvalidHoldBand { step, band } =>
+                write!(f, "Step {}: hold band {} is not positive", step, band),
+            ValidationError::InvalidRampRate { step, rate } =>
+                write!(f, "Step {}: ramp rate {} is not positive", step, rate),
+            ValidationError::RampTooLong { step, duration, max } =>
+                write!(f, "Step {}: ramp of {} s is longer than {} s", step, duration, max),
+            ValidationError::HoldTimeoutTooLong { step, timeout, max } =>
+                write!(f, "Step {}: hold timeout {} s is longer than {} s", step, timeout, max),
+            ValidationError::TotalDurationTooLong { duration, max } =>
+                write!(f, "Total duration {} s is longer than {} s", duration, max),
+        }
+    }
+}
+
+/// Checks the reference series against the given limits.
+/// Returns every problem found, not only the first one.
+/// A ramp in the first step starts from the measured value, so it is checked
+/// as if it started from the limit furthest from the reference.
+pub fn validate(reference_series: &ReferenceSeries, limits: &Limits)
+                -> Result<(), Vec<ValidationError>>
+{
+    let reference_series = reference_series.to_celsius();
+    let mut errors = Vec::new();
+
+    if reference_series.references.is_empty() {
+        errors.push(ValidationError::Empty);
+    }
+
+    // None if the total duration is too long to be represented
+    let mut total = Some(Duration::from_secs(0));
+    let mut previous = None;
+    for (step, reference) in reference_series.references.iter().enumerate() {
+        let duration = reference.duration;
+        let can_be_short = reference.hold.is_some() || reference.ramp.is_some();
+        if duration < limits.min_duration && !can_be_short {
+            errors.push(ValidationError::DurationTooShort { step, duration, min: limits.min_duration });
+        }
+        if duration > limits.max_duration {
+            errors.push(ValidationError::DurationTooLong { step, duration, max: limits.max_duration });
+        }
+
+        let temp = reference.temp;
+        if temp < limits.min_temp {
+            errors.push(ValidationError::TemperatureTooLow { step, temp, min: limits.min_temp });
+        }
+        if temp > limits.max_temp {
+            errors.push(ValidationError::TemperatureTooHigh { step, temp, max: limits.max_temp });
+        }
+
+        if let Some(ref hold) = reference.hold {
+            if !(hold.band > 0.0) {
+                errors.push(ValidationError::InvalidHoldBand { step, band: hold.band });
+            }
+            if let Some(timeout) = hold.timeout {
+                if timeout > limits.max_duration {
+                    errors.push(ValidationError::HoldTimeoutTooLong { step, timeout, max: limits.max_duration });
+                }
+            }
+        }
+        if let Some(Ramp::Rate(rate)) = reference.ramp {
+            if !(rate > 0.0) {
+                errors.push(ValidationError::InvalidRampRate { step, rate });
+            }
+        }
+
+        let from = previous.unwrap_or_else( || {
+            if temp - limits.min_temp > limits.max_temp - temp { limits.min_temp } else { limits.max_temp }
+        });
+        let ramp_time = reference.get_ramp_time(from);
+        if ramp_time > Duration::from_secs(limits.max_duration) {
+            errors.push(ValidationError::RampTooLong {
+                step,
+                duration: ramp_time.as_secs(),
+                max: limits.max_duration,
+            });
+        }
+        total = total
+            .and_then( |total| total.checked_add(Duration::from_secs(duration)) )
+            .and_then( |total| total.checked_add(ramp_time) );
+        previous = Some(temp);
+    }
+
+    let total = total.map_or(u64::max_value(), |total| total.as_secs());
+    if total > limits.max_total_duration {
+        errors.push(ValidationError::TotalDurationTooLong {
+            duration: total,
+            max: limits.max_total_duration,
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::pid::{Reference, Hold, Unit};
+
+    fn reference(duration: u64, temp: f32) -> Reference {
+        Reference { duration, temp, hold: None, ramp: None }
+    }
+
+    fn validate_references(references: Vec<Reference>) -> Vec<ValidationError> {
+        validate(&ReferenceSeries::new(references), &Limits::default()).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn accepts_valid_reference_series() {
+        let mut ramped = reference(60, 72.0);
+        ramped.ramp = Some(Ramp::Rate(1.0));
+        let mut held = reference(0, 78.0);
+        held.hold = Some(Hold { band: 0.5, timeout: Some(600) });
+        assert!(validate_references(vec![reference(3600, 66.0), ramped, held]).is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_reference_series() {
+        match validate_references(Vec::new()).as_slice() {
+            [ValidationError::Empty] => (),
+            errors => panic!("Unexpected errors: {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut ramped = reference(0, 120.0);
+        ramped.ramp = Some(Ramp::Rate(0.0));
+        let mut held = reference(0, -5.0);
+        held.hold = Some(Hold { band: 0.0, timeout: None });
+        let errors = validate_references(vec![reference(0, 66.0), ramped, held]);
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        match errors.as_slice() {
+            [ValidationError::DurationTooShort { step: 0, .. },
+             ValidationError::TemperatureTooHigh { step: 1, .. },
+             ValidationError::InvalidRampRate { step: 1, .. },
+             ValidationError::TemperatureTooLow { step: 2, .. },
+             ValidationError::InvalidHoldBand { step: 2, .. }] => (),
+            errors => panic!("Unexpected errors: {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn converts_to_celsius() {
+        let mut reference_series = ReferenceSeries::new(vec![reference(60, 150.0)]);
+        reference_series.unit = Unit::Fahrenheit;
+        assert!(validate(&reference_series, &Limits::default()).is_ok());
+        reference_series.unit = Unit::Celsius;
+        assert!(validate(&reference_series, &Limits::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_long_ramps_and_hold_timeouts() {
+        let max = Limits::default().max_duration;
+        let mut ramped = reference(60, 66.0);
+        ramped.ramp = Some(Ramp::Duration(max + 1));
+        let mut held = reference(60, 66.0);
+        held.hold = Some(Hold { band: 0.5, timeout: Some(max + 1) });
+        match validate_references(vec![reference(60, 66.0), ramped, held]).as_slice() {
+            [ValidationError::RampTooLong { step: 1, .. },
+             ValidationError::HoldTimeoutTooLong { step: 2, .. }] => (),
+            errors => panic!("Unexpected errors: {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn checks_first_ramp_from_furthest_limit() {
+        // From 0 degrees, the ramp takes 66 000 minutes
+        let mut ramped = reference(60, 66.0);
+        ramped.ramp = Some(Ramp::Rate(0.001));
+        match validate_references(vec![ramped]).as_slice() {
+            [ValidationError::RampTooLong { step: 0, duration: 3_960_000, .. }] => (),
+            errors => panic!("Unexpected errors: {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn rejects_long_total_duration() {
+        let max = Limits::default().max_duration;
+        let references = (0..5).map( |_| reference(max, 66.0) ).collect();
+        match validate_references(references).as_slice() {
+            [ValidationError::TotalDurationTooLong { .. }] => (),
+            errors => panic!("Unexpected errors: {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn does_not_overflow_total_duration() {
+        let references = vec![reference(u64::max_value(), 66.0), reference(u64::max_value(), 66.0)];
+        let errors = validate_references(references);
+        match errors.last() {
+            Some(&ValidationError::TotalDurationTooLong { duration, .. }) =>
+                assert_eq!(duration, u64::max_value()),
+            _ => panic!("Unexpected errors: {:?}", errors),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Module containing the error type returned by the routes of the interface.
+
+use std::io;
+
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::response::status;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+
+use controller::validation::ValidationError;
+use super::revision::WithRevision;
+
+/// Errors that can be returned by the routes.
+#[derive(Debug)]
+pub enum Error {
+    /// Error from the filesystem or a controller. Responds with a status code
+    /// depending on the kind of the error, and the error message as body.
+    Io(io::Error),
+    /// The received reference series is not valid. Responds with a 422 and a
+    /// JSON encoded list of every problem found.
+    Invalid(Vec<ValidationError>),
+    /// A stored reference series was to be replaced without giving the
+    /// revision it is based on. Responds with a 428.
+    RevisionRequired,
+    /// A stored reference series was to be replaced, but it has been changed
+    /// since the given revision. Responds with a 412, and the current revision as ETag.
+    RevisionMismatch(u64),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<Vec<ValidationError>> for Error {
+    fn from(errors: Vec<ValidationError>) -> Error {
+        Error::Invalid(errors)
+    }
+}
+
+impl<'r> Responder<'r> for Error {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            Error::Io(error) => {
+                println!("Responding with error: {}", error);
+                let status = match error.kind() {
+                    io::ErrorKind::NotFound => Status::NotFound,
+                    io::ErrorKind::AlreadyExists => Status::Conflict,
+                    io::ErrorKind::NotConnected => Status::Conflict, // No running process
+                    io::ErrorKind::InvalidInput => Status::BadRequest,
+                    io::ErrorKind::InvalidData => Status::UnprocessableEntity,
+                    _ => Status::InternalServerError,
+                };
+                status::Custom(status, error.to_string()).respond_to(request)
+            },
+            Error::Invalid(errors) => {
+                status::Custom(Status::UnprocessableEntity, Json(errors)).respond_to(request)
+            },
+            Error::RevisionRequired => {
+                let message = "The revision to replace must be given in an If-Match header";
+                status::Custom(Status::PreconditionRequired, message).respond_to(request)
+            },
+            Error::RevisionMismatch(current) => {
+                let message = "The reference series has been changed by someone else";
+                WithRevision(status::Custom(Status::PreconditionFailed, message), current)
+                    .respond_to(request)
+            },
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Module containing types used to respond with logs in the format asked for.
+
+use rocket::Outcome;
+use rocket::http::ContentType;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder};
+use rocket::response::content::Content;
+
+use log::export::Format;
+
+/// Request guard reading the format asked for in the Accept header. The first
+/// supported media type is used, and None if there is no supported media type.
+pub struct AcceptedFormat(pub Option<Format>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for AcceptedFormat {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AcceptedFormat, ()> {
+        let format = request.accept().and_then( |accept| {
+            accept.iter()
+                .filter_map( |media_type| Format::from_media_type(media_type.top().as_str(),
+                                                                  media_type.sub().as_str()) )
+                .next()
+        });
+        Outcome::Success(AcceptedFormat(format))
+    }
+}
+
+/// Responder sending an exported log, with the Content-Type of its format.
+pub struct Exported(pub String, pub Format);
+
+impl<'r> Responder<'r> for Exported {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let (top, sub) = self.1.get_media_type();
+        Content(ContentType::new(top, sub), self.0).respond_to(request)
+    }
+}
@@ -1,67 +1,148 @@
 // use std::fs;
-use std::fs::File;
 use std::io;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, PoisonError};
 
 use rocket::State;
 use rocket_contrib::json::Json;
 
 use controller;
 use controller::{Controller, ReferenceSeries};
+use controller::status::Status;
+use controller::validation;
+use controller::validation::Limits;
+use controller::sensor::Sensor;
+use controller::ds18b20;
+use controller::calibration::{Calibration, CalibrationPoint, CalibrationMap};
 use log;
-use log::LogEntry;
+use log::{LogEntry, LogMetadata, Event, EventEntry};
+use log::query::LogQuery;
+use log::export::Format;
+use log::stats;
+use log::stats::LogStats;
+use storage::{Storage, Name};
+use config::SensorsConfig;
 
-type ResourceMap = HashMap<String, Mutex<Controller>>;
+mod error;
+use self::error::Error;
+
+mod param;
+
+mod format;
+use self::format::{AcceptedFormat, Exported};
+
+mod revision;
+use self::revision::{ReferenceLock, IfMatch, WithRevision};
+
+/// The controllers exposed by the interface, by name. Shared with the retention
+/// policy, which must know the logs in use.
+pub type ResourceMap = Arc<HashMap<String, Mutex<Controller>>>;
+
+type StorageState<'a> = State<'a, Arc<Storage>>;
 
-// TODO: Make custom result type, to give better error messages
 // TODO: Move JSON encoding into respective modules, don't pass Strings around unnecessary
 
 // Called by the application at startup, not part of the API
 
 /// Initialises the web server.
-/// Takes a list of controllers that will be exposed on the internet.
+/// Takes a list of controllers that will be exposed on the internet, the
+/// storage logs, reference series and calibrations are kept in, where to find
+/// sensors, and the calibrations used by the sensors.
 /// Note that this function does not return, unless there were an error starting
 /// the server.
-pub fn init_interface(resources: ResourceMap) 
+pub fn init_interface(resources: ResourceMap, storage: Arc<Storage>, sensors: SensorsConfig,
+                      calibrations: CalibrationMap)
 {
     rocket::ignite()
         .manage(resources)
+        .manage(storage)
+        .manage(sensors)
+        .manage(calibrations)
+        .manage(ReferenceLock::new())
         .mount("/", routes![
             get_list_of_logs,
             get_log,
+            get_log_metadata,
+            get_log_stats,
+            post_note,
             delete_log,
             get_current_values,
+            get_status,
             get_list_of_resources,
+            get_list_of_sensors,
+            get_calibration,
+            put_calibration,
+            delete_calibration,
+            post_calibration_point,
             get_list_of_reference_series,
             get_reference_series,
             delete_reference_series,
             post_reference_series,
-            start_controlling
+            put_reference_series,
+            rename_reference_series,
+            copy_reference_series,
+            start_controlling,
+            stop_controlling,
+            pause_controlling,
+            resume_controlling,
+            next_step,
+            jump_to_step,
+            extend_step
         ])
         .launch();
 }
 
 /// Returns a list of all available logs.
 /// Route: GET /logs
+/// Route: GET /logs?from=<from>&to=<to>
 /// Returns a list of names of available logs encoded in JSON.
 /// ```no_run
 /// ["log1", "log2", "log3"]
 /// ```
+/// If <from> or <to> is given, only the logs with entries, step changes or
+/// events within this range are returned, e.g. every process run during a week.
+/// They are timestamps (# milliseconds since UNIX_EPOCH), as in GET /logs/<name>.
 /// A specific log can be retrieved by sending a GET request to /logs/<name>
-#[get("/logs")]
-fn get_list_of_logs() -> Json<Vec<String>> {
-    Json(log::get_list_of_logs())
+#[get("/logs?<from>&<to>")]
+fn get_list_of_logs(from: Option<u64>, to: Option<u64>, storage: StorageState)
+                    -> io::Result<Json<Vec<Name>>> {
+    if from.is_none() && to.is_none() {
+        return Ok(Json(storage.get_list_of_logs()?));
+    }
+    let query = LogQuery { from, to, ..LogQuery::default() };
+    Ok(Json(storage.get_list_of_logs_in_range(&query)?))
 }
 
-// use query string? i.e. get /logs?<name>
 // can fail if <name> does not exist
 
 /// Returns the log <name>
 /// Route: GET /logs/<name>
-/// Returns a logfile.
-/// Fails if the log specified doesn't exist.
-/// The logfile contains a JSON encoded Log, on the following format:
+/// Route: GET /logs/<name>?from=<from>&to=<to>&points=<points>&fields=<fields>&format=<format>&delimiter=<delimiter>
+/// Returns a log. Logs written by earlier versions are converted.
+/// All query parameters are optional:
+/// <from> and <to> are timestamps (# milliseconds since UNIX_EPOCH), and only
+/// entries and step changes within this range are returned.
+/// <points> is the maximum number of entries returned. Longer logs are split into
+/// <points> buckets of consecutive entries, and the mean of each bucket is returned.
+/// <fields> is a comma separated list of the values to return, e.g. "input,output".
+/// Values that are not selected are left out of the entries.
+/// <format> is "json", "jsonl" (JSON Lines) or "csv". If not given, the format
+/// is taken from the Accept header ("application/json", "application/x-ndjson"
+/// or "text/csv"), and JSON is used if no supported format is accepted.
+/// <delimiter> is the delimiter used in CSV, and defaults to ','.
+/// Fails if the log specified doesn't exist. Names can only contain letters,
+/// digits, '-', '_' and '.', and responds with a 404 for any other name.
+/// Responds with a 400 if <fields> contains an unknown field, if <format> is
+/// unknown, or if <delimiter> is not a single character.
+/// In JSON Lines, every entry, step change and event is a JSON object on its own
+/// line, with a "type" field of "entry", "step_change" or "event", ordered by timestamp.
+/// In CSV, the first line is a header, and every other line is an entry, with
+/// timestamps in ISO 8601 format (UTC), e.g.:
+/// ```no_run
+/// timestamp,reference,input,output
+/// 2018-10-18T12:00:00.000Z,66,64.5,35
+/// ```
+/// Responds with the JSON encoded Log, on the following format:
 /// {
 ///   "reference": String, // The name of the reference series used
 ///   "entries": [
@@ -72,11 +153,134 @@ fn get_list_of_logs() -> Json<Vec<String>> {
 ///       "output": Floating point number // Output of the controller at given point of time
 ///     },
 ///     ... // The rest of the entries are skipped
+///   ],
+///   "step_changes": [ // Changes made to the progress of the process
+///     {
+///       "timestamp": Integer, // # milliseconds sice UNIX_EPOCH
+///       "change": { "Jump": { "from": Integer, "to": Integer } }
+///                 or { "Skip": { "step": Integer } }
+///                 or { "Extend": { "step": Integer, "seconds": Integer } }
+///                 or { "Reached": { "step": Integer, "seconds": Integer } }
+///                 or { "HoldTimeout": { "step": Integer } }
+///     },
+///     ...
+///   ],
+///   "events": [ // Things that happened during the process, and notes
+///     {
+///       "timestamp": Integer, // # milliseconds sice UNIX_EPOCH
+///       "event": { "StepStarted": { "step": Integer, "temp": Floating point number } }
+///                or "Paused" or "Resumed" or "Stopped" or "Finished"
+///                or { "Faulted": { "message": String } }
+///                or { "SensorFailed": { "message": String } }
+///                or { "Note": { "text": String } }
+///     },
+///     ...
 ///   ]
 /// }
-#[get("/logs/<name>")]
-fn get_log(name: String) -> io::Result<File> {
-    log::get_log(name)
+#[get("/logs/<name>?<from>&<to>&<points>&<fields>&<format>&<delimiter>")]
+fn get_log(name: Name,
+           from: Option<u64>,
+           to: Option<u64>,
+           points: Option<usize>,
+           fields: Option<String>,
+           format: Option<String>,
+           delimiter: Option<String>,
+           accepted: AcceptedFormat,
+           storage: StorageState)
+           -> Result<Exported, Error>
+{
+    let fields = match fields {
+        Some(fields) => Some(LogQuery::parse_fields(&fields)?),
+        None => None,
+    };
+    let query = LogQuery { from, to, fields, max_points: points };
+
+    let mut format = match format {
+        Some(format) => format.parse()?,
+        None => accepted.0.unwrap_or(Format::Json),
+    };
+    if let Some(delimiter) = delimiter {
+        let mut chars = delimiter.chars();
+        let c = match (chars.next(), chars.next()) {
+            (Some(c), None) => c,
+            _ => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                     "The delimiter must be a single character"))),
+        };
+        if let Format::Csv { ref mut delimiter } = format {
+            *delimiter = c;
+        }
+    }
+
+    Ok(Exported(log::export_log(&**storage, &name, &query, format)?, format))
+}
+
+/// Returns the metadata of the log <name>
+/// Route: GET /logs/<name>/meta
+/// Fails if the log specified doesn't exist. Logs written by earlier versions
+/// only have a reference, and null for every other field.
+/// Responds with the JSON encoded LogMetadata, on the following format:
+/// {
+///   "reference": String, // The name of the reference series used
+///   "resource": String, // The name of the resource the process was run on
+///   "process": {
+///     "reference_series": ReferenceSeries, // As it was when the process was started
+///     "pid_parameters": {
+///       "kp": Floating point number,
+///       "ki": Floating point number,
+///       "kd": Floating point number,
+///       "max_integrator": Floating point number or null // null means no limit
+///     },
+///     "frequency": Integer, // Hz
+///     "sensor": String, // e.g. "DS18B20 28-000009eab19f"
+///     "output": String
+///   },
+///   "start_time": Integer, // # milliseconds sice UNIX_EPOCH
+///   "end_time": Integer or null // null while the process is running
+/// }
+#[get("/logs/<name>/meta")]
+fn get_log_metadata(name: Name, storage: StorageState) -> Result<Json<LogMetadata>, Error> {
+    Ok(Json(storage.get_log_metadata(&name)?))
+}
+
+/// Returns statistics of how well the process of the log <name> followed its references
+/// Route: GET /logs/<name>/stats?band=<band>
+/// The log is split into segments at every reference change, where a ramp and
+/// the reference following it is a single segment. <band> is optional, and is
+/// the band around the reference (in degrees) the input must stay within to be
+/// considered settled. Defaults to 0.5.
+/// Fails if the log specified doesn't exist.
+/// Responds with the JSON encoded LogStats, on the following format. All times
+/// are in seconds, and relative to the start of the segment:
+/// {
+///   "segments": [
+///     {
+///       "start": Integer, // # milliseconds sice UNIX_EPOCH
+///       "end": Integer, // # milliseconds sice UNIX_EPOCH
+///       "reference": Floating point number, // At the end of a ramp
+///       "initial_input": Floating point number,
+///       "rise_time": Floating point number or null, // From 10% to 90% of the change
+///       "overshoot": Floating point number, // Degrees past the reference
+///       "settling_time": Floating point number or null, // null if never settled
+///       "steady_state_error": Floating point number, // Mean over the last 10% of the segment
+///       "iae": Floating point number, // Integral of absolute error
+///       "ise": Floating point number, // Integral of squared error
+///       "time_at_max_output": Floating point number,
+///       "full_output_seconds": Floating point number // Energy used, as seconds at full output
+///     },
+///     ...
+///   ],
+///   "duration": Floating point number,
+///   "iae": Floating point number,
+///   "ise": Floating point number,
+///   "time_at_max_output": Floating point number,
+///   "full_output_seconds": Floating point number
+/// }
+#[get("/logs/<name>/stats?<band>")]
+fn get_log_stats(name: Name, band: Option<f32>, storage: StorageState)
+                 -> Result<Json<LogStats>, Error>
+{
+    let log = storage.get_log(&name, &LogQuery::default())?;
+    Ok(Json(stats::analyse(&log, band.unwrap_or(stats::DEFAULT_SETTLING_BAND))))
 }
 
 // can fail if <name> does not exist
@@ -85,11 +289,14 @@ fn get_log(name: String) -> io::Result<File> {
 /// Route: DELETE /logs/<name>
 /// Fails if the logfile is in use by a currently running process.
 #[delete("/logs/<name>")]
-fn delete_log(name: String, resources: State<ResourceMap>) -> io::Result<()> {
+fn delete_log(name: Name, resources: State<ResourceMap>, storage: StorageState)
+              -> io::Result<()>
+{
     println!("Deleting log {}", name);
-    for (_, controller) in &(*resources) {
-        if !controller.lock().unwrap().get_name_of_current_process().is_none()
-           && controller.lock().unwrap().get_name_of_current_process().unwrap() == name
+    for controller in resources.values() {
+        let controller = controller.lock().unwrap_or_else(PoisonError::into_inner);
+        if !controller.get_name_of_current_process().is_none()
+           && controller.get_name_of_current_process().unwrap() == name
         {
             println!("Log in use");
             return Err(io::Error::new(
@@ -99,9 +306,49 @@ fn delete_log(name: String, resources: State<ResourceMap>) -> io::Result<()> {
         }
     }
     // As no controller in uses the file, it can safely be deleted
-    // Move this to log module?
-    println!("Removing file");
-    log::delete_log(name)
+    println!("Removing log");
+    storage.delete_log(&name)
+}
+
+/// A note received by post_note.
+#[derive(Deserialize)]
+struct Note {
+    text: String,
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+/// Adds a note to the log <name>
+/// Route: POST /logs/<name>/notes
+/// The note is JSON encoded, on the following format:
+/// {
+///   "text": String, // e.g. "Grain added"
+///   "timestamp": Integer // Optional, # milliseconds sice UNIX_EPOCH. Defaults to now
+/// }
+/// Notes can be added both while the process is running and after it has ended.
+/// Fails if the log specified doesn't exist. Responds with a 400 if the text is
+/// empty, and with a 422 if the log was written by an earlier version.
+#[post("/logs/<name>/notes", format = "json", data = "<note>")]
+fn post_note(name: Name, note: Json<Note>, resources: State<ResourceMap>, storage: StorageState)
+             -> Result<(), Error>
+{
+    let note = note.into_inner();
+    if note.text.trim().is_empty() {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "The note is empty")));
+    }
+    let event = Event::Note { text: note.text };
+    let event = match note.timestamp {
+        Some(timestamp) => EventEntry::with_timestamp(event, timestamp),
+        None => EventEntry::new(event),
+    };
+
+    // The log of a running process must be written by its Logger
+    for controller in resources.values() {
+        if controller.lock().unwrap_or_else(PoisonError::into_inner).add_event(&name, &event)? {
+            return Ok(());
+        }
+    }
+    Ok(storage.add_event(&name, &event)?)
 }
 
 // probably not use query string?
@@ -124,8 +371,9 @@ fn get_current_values(resource: String, resources: State<ResourceMap>)
     println!("Getting current values for: {}", resource);
     if let Some(controller) = resources.get(&resource) {
         println!("Resource exists");
-        println!("Last entry: {:?}", controller.lock().unwrap().get_last_log_entry());
-        if let Some(entry) = controller.lock().unwrap().get_last_log_entry() {
+        let controller = controller.lock().unwrap_or_else(PoisonError::into_inner);
+        println!("Last entry: {:?}", controller.get_last_log_entry());
+        if let Some(entry) = controller.get_last_log_entry() {
             Some(Json(entry))
         } else {
             None
@@ -135,6 +383,27 @@ fn get_current_values(resource: String, resources: State<ResourceMap>)
     }
 }
 
+/// Returns the state and progress of the given controller
+/// Route: GET /<resource>/status
+/// Returns a JSON encoded status on the following format:
+/// {
+///   "state": String, // One of Idle, Running, Paused, Finished or Faulted
+///   "step": Integer or null, // Index of the current step in the reference series
+///   "waiting": Boolean, // True while a hold step waits for the reference to be reached
+///   "reference": Floating point number or null, // The current setpoint
+///   "step_elapsed": Integer, // Seconds spent in the current step
+///   "step_remaining": Integer, // Seconds left of the current step
+///   "total_elapsed": Integer, // Seconds spent in the whole reference series
+///   "total_remaining": Integer // Seconds left of the whole reference series
+/// }
+/// Responds with a 404 if the given controller doesn't exist.
+#[get("/<resource>/status", rank = 2)]
+fn get_status(resource: String, resources: State<ResourceMap>) -> Option<Json<Status>> {
+    let controller = resources.get(&resource)?;
+    let status = controller.lock().unwrap_or_else(PoisonError::into_inner).get_status();
+    Some(Json(status))
+}
+
 /// Returns a list of the name of all controllers.
 /// Route: GET /resources
 /// The controllers are made at compile time, and each got its own Sensor and
@@ -145,26 +414,203 @@ fn get_list_of_resources(resources: State<ResourceMap>) -> Json<Vec<String>> {
     Json((*resources).keys().cloned().collect())
 }
 
+/// A DS18B20 returned by GET /sensors.
+#[derive(Serialize)]
+struct SensorReading {
+    id: String,
+    aliases: Vec<String>,
+    temperature: Option<f32>,
+    uncalibrated: Option<f32>,
+    error: Option<String>,
+}
+
+/// Returns every DS18B20 connected, and every DS18B20 with an alias in the
+/// config file, with a new reading.
+/// Route: GET /sensors
+/// Returns a JSON encoded list sorted by id, on the following format:
+/// [
+///   {
+///     "id": String, // Such as "28-000009eab19f"
+///     "aliases": [String, ...], // The names the sensor has in the config file
+///     "temperature": Floating point number or null, // null if the sensor failed
+///     "uncalibrated": Floating point number or null, // The temperature before calibration
+///     "error": String or null // Why the sensor failed, such as when it isn't connected
+///   },
+///   ...
+/// ]
+/// Responds with a 404 if the directory of onewire devices doesn't exist.
+#[get("/sensors")]
+fn get_list_of_sensors(sensors: State<SensorsConfig>, calibrations: State<CalibrationMap>)
+                       -> io::Result<Json<Vec<SensorReading>>> {
+    let directory = sensors.get_directory();
+    let mut ids = ds18b20::discover(&directory).map_err( |e| {
+        io::Error::new(e.kind(), format!("Unable to list {}: {}", directory.display(), e))
+    })?;
+    ids.extend(sensors.aliases.values().cloned());
+    ids.sort();
+    ids.dedup();
+
+    let readings = ids.into_iter().map( |id| {
+        let result = sensors.build_ds18b20(&id).read();
+        let calibration = calibrations.read().unwrap_or_else(PoisonError::into_inner)
+            .get(&id).cloned().unwrap_or_default();
+        SensorReading {
+            aliases: sensors.get_aliases(&id),
+            id,
+            temperature: result.clone().ok().map( |temperature| calibration.apply(temperature) ),
+            uncalibrated: result.clone().ok(),
+            error: result.err().map( |e| e.to_string() ),
+        }
+    }).collect();
+    Ok(Json(readings))
+}
+
+/// Helper function returning the id of the DS18B20 with the given id or alias.
+/// Fails with NotFound if it isn't a DS18B20.
+fn resolve_sensor(sensors: &SensorsConfig, id: &Name) -> io::Result<Name> {
+    let resolved = sensors.resolve(id.as_str());
+    if !resolved.starts_with(ds18b20::ID_PREFIX) {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  format!("{} is neither the id of a DS18B20 nor a sensor alias", id)));
+    }
+    Ok(Name::new(resolved)?)
+}
+
+/// Helper function validating and storing the calibration of a sensor, which
+/// is used by the sensor at once.
+fn store_calibration(storage: &Storage, calibrations: &mut HashMap<String, Calibration>,
+                     id: &Name, calibration: Calibration) -> io::Result<()> {
+    calibration.validate().map_err( |e| io::Error::new(io::ErrorKind::InvalidData, e) )?;
+    storage.store_calibration(id, &calibration)?;
+    calibrations.insert(id.as_str().to_owned(), calibration);
+    Ok(())
+}
+
+/// Returns the calibration of a sensor.
+/// Route: GET /sensors/<id>/calibration
+/// <id> is the id of a DS18B20, or an alias of it. Returns a JSON encoded
+/// calibration on the following format:
+/// {
+///   "offset": Floating point number, // Added to the temperature read, after the gain
+///   "gain": Floating point number,
+///   "points": [ // Sorted by read. If there are any, the offset and gain must be 0 and 1
+///     {
+///       "read": Floating point number, // What the sensor read, before calibration
+///       "reference": Floating point number // The real temperature
+///     },
+///     ...
+///   ]
+/// }
+/// With one point, temperatures are shifted by the error at the point. With
+/// more points, the correction is interpolated linearly between the points.
+/// Responds with a 404 if the sensor has no calibration.
+#[get("/sensors/<id>/calibration")]
+fn get_calibration(id: Name, sensors: State<SensorsConfig>, calibrations: State<CalibrationMap>)
+                   -> Result<Json<Calibration>, Error> {
+    let id = resolve_sensor(&sensors, &id)?;
+    let calibrations = calibrations.read().unwrap_or_else(PoisonError::into_inner);
+    match calibrations.get(id.as_str()) {
+        Some(calibration) => Ok(Json(calibration.clone())),
+        None => Err(io::Error::new(io::ErrorKind::NotFound,
+                                   format!("{} has no calibration", id)).into()),
+    }
+}
+
+/// Replaces the calibration of a sensor, which is used at once.
+/// Route: PUT /sensors/<id>/calibration
+/// Takes a JSON encoded calibration, on the format returned by
+/// GET /sensors/<id>/calibration. Fields that are left out are taken from a
+/// calibration leaving temperatures unchanged.
+/// Responds with a 422 if the gain is not positive, if there are points and
+/// another offset or gain, or if the points are not sorted by the temperature read.
+#[put("/sensors/<id>/calibration", data = "<calibration>")]
+fn put_calibration(id: Name, calibration: Json<Calibration>, sensors: State<SensorsConfig>,
+                   calibrations: State<CalibrationMap>, storage: StorageState)
+                   -> Result<(), Error> {
+    let id = resolve_sensor(&sensors, &id)?;
+    let mut calibrations = calibrations.write().unwrap_or_else(PoisonError::into_inner);
+    store_calibration(&**storage, &mut calibrations, &id, calibration.into_inner())?;
+    Ok(())
+}
+
+/// Removes the calibration of a sensor, whose temperatures are then used as read.
+/// Route: DELETE /sensors/<id>/calibration
+/// Responds with a 404 if the sensor has no calibration.
+#[delete("/sensors/<id>/calibration")]
+fn delete_calibration(id: Name, sensors: State<SensorsConfig>,
+                      calibrations: State<CalibrationMap>, storage: StorageState)
+                      -> Result<(), Error> {
+    let id = resolve_sensor(&sensors, &id)?;
+    let mut calibrations = calibrations.write().unwrap_or_else(PoisonError::into_inner);
+    storage.delete_calibration(&id)?;
+    calibrations.remove(id.as_str());
+    Ok(())
+}
+
+/// A point received by POST /sensors/<id>/calibration/points.
+#[derive(Deserialize)]
+struct NewCalibrationPoint {
+    reference: f32,
+    read: Option<f32>,
+}
+
+/// Records that a sensor reads a temperature when the real temperature is
+/// known, such as when the probe is in boiling water.
+/// Route: POST /sensors/<id>/calibration/points
+/// Takes a JSON encoded point on the following format:
+/// {
+///   "reference": Floating point number, // The real temperature
+///   "read": Floating point number // Optional, the sensor is read if left out
+/// }
+/// The point is added to the calibration of the sensor, replacing any point
+/// with the same reference or temperature read. The calibration is stored, and
+/// used at once. Returns the new calibration, on the format returned by
+/// GET /sensors/<id>/calibration.
+/// Responds with a 422 if the calibration has another offset or gain than 0
+/// and 1, and with a 500 if the sensor could not be read.
+#[post("/sensors/<id>/calibration/points", data = "<point>")]
+fn post_calibration_point(id: Name, point: Json<NewCalibrationPoint>,
+                          sensors: State<SensorsConfig>, calibrations: State<CalibrationMap>,
+                          storage: StorageState)
+                          -> Result<Json<Calibration>, Error> {
+    let id = resolve_sensor(&sensors, &id)?;
+    let read = match point.read {
+        Some(read) => read,
+        None => sensors.build_ds18b20(id.as_str()).read().map_err( |e| {
+            io::Error::new(io::ErrorKind::Other, format!("Unable to read {}: {}", id, e))
+        })?,
+    };
+
+    let mut calibrations = calibrations.write().unwrap_or_else(PoisonError::into_inner);
+    let mut calibration = calibrations.get(id.as_str()).cloned().unwrap_or_default();
+    calibration.add_point(CalibrationPoint { read, reference: point.reference });
+    store_calibration(&**storage, &mut calibrations, &id, calibration.clone())?;
+    Ok(Json(calibration))
+}
+
 /// Returns a list of saved reference series.
 /// Route: GET /reference_series
 /// Returns a JSON encoded list of names of saved reference series.
 #[get("/reference_series")]
-fn get_list_of_reference_series() -> Json<Vec<String>> {
-    Json(controller::get_list_of_reference_series())
+fn get_list_of_reference_series(storage: StorageState) -> io::Result<Json<Vec<Name>>> {
+    Ok(Json(storage.get_list_of_reference_series()?))
 }
 
 // can fail if <name> does not exist
-// TODO: return File?
 
 /// Returns the given reference series.
 /// Route: GET /reference_series/<name>
-/// Returns the JSON encoded reference series given by <name>.
-/// A list of available reference series can be found by sending a GET request
-/// to /reference_series. Fails if the given reference series doesn't exist, or
-/// other filesystem error.
+/// Returns the JSON encoded reference series given by <name>, with its revision
+/// as ETag. A list of available reference series can be found by sending a GET
+/// request to /reference_series. Fails if the given reference series doesn't
+/// exist, or other filesystem error.
 #[get("/reference_series/<name>", rank = 1)]
-fn get_reference_series(name: String) -> io::Result<String> {
-    controller::get_reference_series(&name)
+fn get_reference_series(name: Name, storage: StorageState)
+                        -> Result<WithRevision<Json<ReferenceSeries>>, Error>
+{
+    let reference_series = storage.get_reference_series(&name)?;
+    let revision = reference_series.revision;
+    Ok(WithRevision(Json(reference_series), revision))
 }
 
 // delete /reference_series/<name>
@@ -174,8 +620,8 @@ fn get_reference_series(name: String) -> io::Result<String> {
 /// Route: DELETE /reference_series/<name>
 /// Fails if the reference series doesn't exist or other filesystem error.
 #[delete("/reference_series/<name>")]
-fn delete_reference_series(name: String) -> io::Result<()> {
-    controller::delete_reference_series(name)
+fn delete_reference_series(name: Name, storage: StorageState) -> io::Result<()> {
+    storage.delete_reference_series(&name)
 }
 
 // can fail if <name> exists
@@ -183,21 +629,108 @@ fn delete_reference_series(name: String) -> io::Result<()> {
 /// Save a new reference series
 /// Route: POST /reference_series/<name>
 /// Fails if a reference series with the same name already exists.
+/// Responds with a 422 if the reference series is not valid, with a JSON encoded
+/// list of every problem found. The reference series is checked against the
+/// default limits, the limits of each resource are checked when it is started.
+/// The reference series is JSON encoded on the following format:
+/// {
+///   "unit": String, // Celsius or Fahrenheit, defaults to Celsius
+///   "references": [
+///     {
+///       "duration": Integer, // # seconds the reference is held
+///       "temp": Floating point number, // The reference
+///       "hold": { "band": Floating point number, "timeout": Integer or null } or null,
+///       "ramp": { "Rate": Floating point number } or { "Duration": Integer } or null
+///     },
+///     ...
+///   ],
+///   "revision": Integer // Set by the server, ignored when received
+/// }
+/// A plain list of references in degrees Celsius is also accepted.
+/// The stored reference series gets revision 1, which is returned as ETag.
 #[post("/reference_series/<name>", data = "<reference_series>")]
-fn post_reference_series(name: String, reference_series: Json<ReferenceSeries>)
-                         -> io::Result<()>
+fn post_reference_series(name: Name, reference_series: Json<ReferenceSeries>,
+                         lock: State<ReferenceLock>, storage: StorageState)
+                         -> Result<WithRevision<()>, Error>
 {
-    // If file exists, return error
-    for file in controller::get_list_of_reference_series() {
+    let _guard = lock.0.lock().unwrap_or_else(PoisonError::into_inner);
+    // If the reference series exists, return error
+    for file in storage.get_list_of_reference_series()? {
         if file == name {
             return Err(io::Error::new(io::ErrorKind::AlreadyExists,
-                                      "The reference series already exists"));
+                                      "The reference series already exists").into());
         }
     }
-    // TODO: Check that received JSON is valid
-    // println!("recv'd reference JSON:{:?}", reference_series.into_inner());
-    // Write json to file
-    controller::store_reference_series(name, reference_series.into_inner())
+    let mut reference_series = reference_series.into_inner();
+    validation::validate(&reference_series, &Limits::default())?;
+    reference_series.revision = 1;
+    storage.store_reference_series(&name, &reference_series)?;
+    Ok(WithRevision((), 1))
+}
+
+/// Replace a stored reference series
+/// Route: PUT /reference_series/<name>
+/// The request must have an If-Match header containing the revision the new
+/// reference series is based on, as returned in the ETag of
+/// GET /reference_series/<name>, or "*" to replace any revision. Responds with
+/// a 428 if the header is missing, with a 400 if it is malformed, and with a 412
+/// and the current revision as ETag if the reference series has been changed
+/// since that revision. Responds with a 422 if the reference series is not
+/// valid, in the same way as POST /reference_series/<name>.
+/// The new revision is returned as ETag.
+/// Fails if the reference series doesn't exist.
+#[put("/reference_series/<name>", data = "<reference_series>")]
+fn put_reference_series(name: Name, reference_series: Json<ReferenceSeries>,
+                        if_match: IfMatch, lock: State<ReferenceLock>, storage: StorageState)
+                        -> Result<WithRevision<()>, Error>
+{
+    let _guard = lock.0.lock().unwrap_or_else(PoisonError::into_inner);
+    let current = storage.get_reference_series(&name)?.revision;
+    if let IfMatch::Missing = if_match {
+        return Err(Error::RevisionRequired);
+    }
+    if !if_match.matches(current) {
+        return Err(Error::RevisionMismatch(current));
+    }
+    let mut reference_series = reference_series.into_inner();
+    validation::validate(&reference_series, &Limits::default())?;
+    reference_series.revision = current + 1;
+    storage.store_reference_series(&name, &reference_series)?;
+    Ok(WithRevision((), current + 1))
+}
+
+/// Rename a stored reference series
+/// Route: POST /reference_series/<name>/rename/<new_name>
+/// If the request has an If-Match header, the reference series is only renamed
+/// if it has not been changed since the given revision. Responds with a 400 if
+/// the header is malformed.
+/// Fails if the reference series doesn't exist, or <new_name> is already in use.
+#[post("/reference_series/<name>/rename/<new_name>")]
+fn rename_reference_series(name: Name, new_name: Name,
+                           if_match: IfMatch, lock: State<ReferenceLock>, storage: StorageState)
+                           -> Result<(), Error>
+{
+    let _guard = lock.0.lock().unwrap_or_else(PoisonError::into_inner);
+    let current = storage.get_reference_series(&name)?.revision;
+    if !if_match.matches(current) {
+        return Err(Error::RevisionMismatch(current));
+    }
+    storage.rename_reference_series(&name, &new_name)?;
+    Ok(())
+}
+
+/// Copy a stored reference series
+/// Route: POST /reference_series/<name>/copy/<new_name>
+/// The copy gets revision 1, which is returned as ETag.
+/// Fails if the reference series doesn't exist, or <new_name> is already in use.
+#[post("/reference_series/<name>/copy/<new_name>")]
+fn copy_reference_series(name: Name, new_name: Name, lock: State<ReferenceLock>,
+                         storage: StorageState)
+                         -> Result<WithRevision<()>, Error>
+{
+    let _guard = lock.0.lock().unwrap_or_else(PoisonError::into_inner);
+    controller::copy_reference_series(&**storage, &name, &new_name)?;
+    Ok(WithRevision((), 1))
 }
 
 // get /start/<resource>/<profile>
@@ -210,16 +743,130 @@ fn post_reference_series(name: String, reference_series: Json<ReferenceSeries>)
 /// <resource> is the name of the controller to be used (i.e. one of the names from
 /// GET /resources). <profile> is the name of a reference profile stored using
 /// POST /references<name>. To get a list of stored refence profiles use GET /reference_series.
+/// Responds with the JSON encoded name of the log of the process, which is
+/// unique for every process started.
+/// Fails if the resource is already running a process. Responds with a 422 if
+/// the reference profile is not within the limits of the resource, with a JSON
+/// encoded list of every problem found.
 #[get("/start/<resource>/<profile>")]
-fn start_controlling(resource: String, profile: String, resource_map: State<ResourceMap>) -> Option<()> {
-    // TODO: replace file operations with calls to reference module
+fn start_controlling(resource: String, profile: Name, resource_map: State<ResourceMap>,
+                     storage: StorageState)
+                     -> Option<Result<Json<Name>, Error>>
+{
     println!("Starting controlling");
-    let reference_series: ReferenceSeries = serde_json::from_str(
-        &controller::get_reference_series(&profile).expect("Unable to read stored profile")
-    ).unwrap();
+    let controller = resource_map.get(&resource)?;
+    Some(start_with_profile(&**storage, &resource, controller, profile).map(Json))
+}
+
+/// Helper function for starting a controller with a stored reference profile.
+fn start_with_profile(storage: &Storage, resource: &str, controller: &Mutex<Controller>,
+                      profile: Name)
+                      -> Result<Name, Error>
+{
+    let resource = Name::new(resource).map_err(io::Error::from)?;
+    let reference_series = storage.get_reference_series(&profile)?;
+
+    let mut controller = controller.lock().unwrap_or_else(PoisonError::into_inner);
+    controller.validate(&reference_series)?;
+    Ok(controller.start(storage, &resource, profile, reference_series)?)
+}
 
+// The rank is needed due to conflict with POST /reference_series/<name>
+
+/// Stop the process running on the given resource.
+/// Route: POST /<resource>/stop
+/// The output of the resource is turned off when the process has stopped.
+/// Responds with a 404 if the given resource doesn't exist, and with a 409 if
+/// there is no process running on the resource.
+#[post("/<resource>/stop", rank = 2)]
+fn stop_controlling(resource: String, resource_map: State<ResourceMap>)
+                    -> Option<Result<(), Error>>
+{
+    println!("Stopping controlling");
+    let controller = resource_map.get(&resource)?;
+    let result = controller.lock().unwrap_or_else(PoisonError::into_inner).stop();
+    Some(result.map_err(Error::from))
+}
+
+/// Pause the process running on the given resource.
+/// Route: POST /<resource>/pause
+/// The current reference is held, but the process doesn't advance to the next
+/// reference until it is resumed using POST /<resource>/resume.
+/// Responds with a 404 if the given resource doesn't exist, and with a 409 if
+/// there is no process running on the resource.
+#[post("/<resource>/pause", rank = 2)]
+fn pause_controlling(resource: String, resource_map: State<ResourceMap>)
+                     -> Option<Result<(), Error>>
+{
+    println!("Pausing controlling");
     let controller = resource_map.get(&resource)?;
-    controller.lock().unwrap().start(profile, reference_series).unwrap(); // TODO: find a way to return this error, rather than panic
+    let result = controller.lock().unwrap_or_else(PoisonError::into_inner).pause();
+    Some(result.map_err(Error::from))
+}
 
-    Some(())
+/// Resume the paused process on the given resource.
+/// Route: POST /<resource>/resume
+/// Responds with a 404 if the given resource doesn't exist, and with a 409 if
+/// there is no process running on the resource.
+#[post("/<resource>/resume", rank = 2)]
+fn resume_controlling(resource: String, resource_map: State<ResourceMap>)
+                      -> Option<Result<(), Error>>
+{
+    println!("Resuming controlling");
+    let controller = resource_map.get(&resource)?;
+    let result = controller.lock().unwrap_or_else(PoisonError::into_inner).resume();
+    Some(result.map_err(Error::from))
+}
+
+// The rank is needed due to conflict with POST /reference_series/<name>
+
+/// Skip the rest of the current step of the process on the given resource.
+/// Route: POST /<resource>/next
+/// If the current step is the last one, the process finishes.
+/// The skip is recorded in the log of the process.
+/// Responds with a 404 if the given resource doesn't exist, and with a 409 if
+/// there is no process running on the resource.
+#[post("/<resource>/next", rank = 2)]
+fn next_step(resource: String, resource_map: State<ResourceMap>)
+             -> Option<Result<(), Error>>
+{
+    println!("Skipping to next step");
+    let controller = resource_map.get(&resource)?;
+    let result = controller.lock().unwrap_or_else(PoisonError::into_inner).next_step();
+    Some(result.map_err(Error::from))
+}
+
+// The rank is needed due to conflict with POST /logs/<name>/notes
+
+/// Jump to the given step of the process on the given resource.
+/// Route: POST /<resource>/jump/<step>
+/// <step> is the index of the step in the reference series, starting at 0.
+/// The jump is recorded in the log of the process.
+/// Responds with a 404 if the given resource doesn't exist, with a 409 if there
+/// is no process running on the resource, and with a 400 if the reference series
+/// has no such step.
+#[post("/<resource>/jump/<step>", rank = 2)]
+fn jump_to_step(resource: String, step: usize, resource_map: State<ResourceMap>)
+                -> Option<Result<(), Error>>
+{
+    println!("Jumping to step {}", step);
+    let controller = resource_map.get(&resource)?;
+    let result = controller.lock().unwrap_or_else(PoisonError::into_inner).jump_to_step(step);
+    Some(result.map_err(Error::from))
+}
+
+/// Extend the current step of the process on the given resource.
+/// Route: POST /<resource>/extend/<seconds>
+/// The duration of the current step is increased by <seconds>.
+/// The extension is recorded in the log of the process.
+/// Responds with a 404 if the given resource doesn't exist, and with a 409 if
+/// there is no process running on the resource.
+#[post("/<resource>/extend/<seconds>", rank = 2)]
+fn extend_step(resource: String, seconds: u64, resource_map: State<ResourceMap>)
+               -> Option<Result<(), Error>>
+{
+    println!("Extending current step by {} s", seconds);
+    let controller = resource_map.get(&resource)?;
+    let result = controller.lock().unwrap_or_else(PoisonError::into_inner).extend_step(seconds);
+    Some(result.map_err(Error::from))
 }
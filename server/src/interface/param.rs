@@ -0,0 +1,42 @@
+//! Module containing implementations needed to use our types as parts of a route.
+
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+
+use storage::{Name, NameError};
+
+/// Names in routes are validated, so they can safely be used to access stored
+/// files. A request with an invalid name is forwarded, and responds with a 404
+/// if no other route matches.
+impl<'a> FromParam<'a> for Name {
+    type Error = NameError;
+
+    fn from_param(param: &'a RawStr) -> Result<Name, NameError> {
+        let param = param.percent_decode().map_err(|_| NameError::InvalidCharacter('%'))?;
+        Name::new(&param)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_param(param: &str) -> Result<Name, NameError> {
+        Name::from_param(RawStr::from_str(param))
+    }
+
+    #[test]
+    fn decodes_names() {
+        assert_eq!(from_param("mash-66.5").unwrap().as_str(), "mash-66.5");
+        assert_eq!(from_param("%20ipa%20").unwrap().as_str(), "ipa");
+    }
+
+    #[test]
+    fn rejects_encoded_paths() {
+        assert_eq!(from_param("%2E%2E"), Err(NameError::InvalidDots));
+        assert_eq!(from_param("..%2Fetc%2Fpasswd"), Err(NameError::InvalidCharacter('/')));
+        assert_eq!(from_param("..%5Cboot.ini"), Err(NameError::InvalidCharacter('\\')));
+        assert_eq!(from_param("ipa%00"), Err(NameError::InvalidCharacter('\0')));
+        assert_eq!(from_param("%FF"), Err(NameError::InvalidCharacter('%')));
+    }
+}
@@ -0,0 +1,85 @@
+//! Module containing types used for optimistic concurrency control of stored
+//! reference series. The revision of a reference series is sent as an ETag,
+//! and must be sent back in an If-Match header when it is replaced.
+
+use std::sync::Mutex;
+
+use rocket::Outcome;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+
+/// Lock held while a stored reference series is checked and written, so no
+/// other request can change it in between.
+pub struct ReferenceLock(pub Mutex<()>);
+
+impl ReferenceLock {
+    pub fn new() -> ReferenceLock {
+        ReferenceLock(Mutex::new(()))
+    }
+}
+
+/// Request guard reading the revisions in the If-Match header. Responds with a
+/// 400 if the header is malformed. Weak tags are not accepted, as they never
+/// match when replacing a reference series.
+pub enum IfMatch {
+    /// There is no If-Match header.
+    Missing,
+    /// The header is "*", which matches any revision.
+    Any,
+    /// A comma separated list of revisions. Both quoted (as sent in the ETag)
+    /// and unquoted revisions are accepted.
+    Revisions(Vec<u64>),
+}
+
+impl IfMatch {
+    /// Returns true if the header matches the given revision. A missing header
+    /// matches any revision.
+    pub fn matches(&self, revision: u64) -> bool {
+        match *self {
+            IfMatch::Missing | IfMatch::Any => true,
+            IfMatch::Revisions(ref revisions) => revisions.contains(&revision),
+        }
+    }
+
+    /// Parses the value of an If-Match header. Returns None if it is malformed.
+    fn parse(value: &str) -> Option<IfMatch> {
+        if value.trim() == "*" {
+            return Some(IfMatch::Any);
+        }
+        value.split(',')
+            .map( |tag| {
+                let tag = tag.trim();
+                let quoted = tag.len() >= 2 && tag.starts_with('"') && tag.ends_with('"');
+                let revision = if quoted { &tag[1..tag.len() - 1] } else { tag };
+                revision.parse().ok()
+            })
+            .collect::<Option<Vec<u64>>>()
+            .map(IfMatch::Revisions)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<IfMatch, ()> {
+        match request.headers().get_one("If-Match") {
+            None => Outcome::Success(IfMatch::Missing),
+            Some(value) => match IfMatch::parse(value) {
+                Some(if_match) => Outcome::Success(if_match),
+                None => Outcome::Failure((Status::BadRequest, ())),
+            },
+        }
+    }
+}
+
+/// Responder adding the given revision as an ETag to the response.
+pub struct WithRevision<R>(pub R, pub u64);
+
+impl<'r, R: Responder<'r>> Responder<'r> for WithRevision<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(self.0.respond_to(request)?)
+            .raw_header("ETag", format!("\"{}\"", self.1))
+            .ok()
+    }
+}
@@ -0,0 +1,123 @@
+//! Module containing functionality for exporting logs to other formats, such as
+//! CSV for spreadsheets.
+
+use std::io;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+
+use super::{StepChangeEntry, EventEntry};
+use super::query::{LogSelection, SelectedEntry};
+
+/// The formats a log can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// The JSON encoded LogSelection, as returned by GET /logs/<name>.
+    Json,
+    /// One JSON object per line. Entries, step changes and events are ordered by
+    /// timestamp, and have a "type" field that is "entry", "step_change" or "event".
+    JsonLines,
+    /// One line per entry, starting with a header. Timestamps are in ISO 8601
+    /// format, in UTC. Step changes and events are not included.
+    Csv { delimiter: char },
+}
+
+impl Format {
+    /// Returns the media type of the format, as used in Accept and Content-Type headers.
+    pub fn get_media_type(&self) -> (&'static str, &'static str) {
+        match *self {
+            Format::Json => ("application", "json"),
+            Format::JsonLines => ("application", "x-ndjson"),
+            Format::Csv { .. } => ("text", "csv"),
+        }
+    }
+
+    /// Returns the format with the given media type, if it is supported.
+    pub fn from_media_type(top: &str, sub: &str) -> Option<Format> {
+        match (top.to_lowercase().as_str(), sub.to_lowercase().as_str()) {
+            ("application", "json") => Some(Format::Json),
+            ("application", "x-ndjson") | ("application", "jsonl") => Some(Format::JsonLines),
+            ("text", "csv") => Some(Format::Csv { delimiter: ',' }),
+            _ => None,
+        }
+    }
+}
+
+/// "json", "jsonl" or "csv". CSV uses ',' as delimiter.
+impl FromStr for Format {
+    type Err = io::Error;
+
+    fn from_str(format: &str) -> io::Result<Format> {
+        match format.trim() {
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv { delimiter: ',' }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("Unknown format: {}", format))),
+        }
+    }
+}
+
+/// A single line of a log exported as JSON Lines.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line<'a> {
+    Entry(&'a SelectedEntry),
+    StepChange(&'a StepChangeEntry),
+    Event(&'a EventEntry),
+}
+
+/// Encodes the selected part of a log in the given format.
+pub fn export(selection: &LogSelection, format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string(selection).expect("Unable to make JSON"),
+        Format::JsonLines => export_json_lines(selection),
+        Format::Csv { delimiter } => export_csv(selection, delimiter),
+    }
+}
+
+/// Helper function encoding a log as JSON Lines.
+fn export_json_lines(selection: &LogSelection) -> String {
+    let mut lines: Vec<(u64, Line)> = selection.get_entries().iter()
+        .map( |entry| (entry.get_timestamp(), Line::Entry(entry)) )
+        .chain(selection.get_step_changes().iter()
+               .map( |step_change| (step_change.timestamp, Line::StepChange(step_change)) ))
+        .chain(selection.get_events().iter()
+               .map( |event| (event.timestamp, Line::Event(event)) ))
+        .collect();
+    lines.sort_by_key( |&(timestamp, _)| timestamp ); // Stable, so entries stay in order
+
+    let mut result = String::new();
+    for (_, line) in lines {
+        result.push_str(&serde_json::to_string(&line).expect("Unable to make JSON"));
+        result.push('\n');
+    }
+    result
+}
+
+/// Helper function encoding the entries of a log as CSV.
+fn export_csv(selection: &LogSelection, delimiter: char) -> String {
+    let delimiter = delimiter.to_string();
+
+    let mut header = vec!["timestamp"];
+    header.extend(selection.get_fields().iter().map( |field| field.get_name() ));
+    let mut result = header.join(&delimiter);
+    result.push('\n');
+
+    for entry in selection.get_entries() {
+        let mut line = vec![format_timestamp(entry.get_timestamp())];
+        for field in selection.get_fields() {
+            line.push(entry.get(*field).map( |value| value.to_string() ).unwrap_or_default());
+        }
+        result.push_str(&line.join(&delimiter));
+        result.push('\n');
+    }
+    result
+}
+
+/// Helper function formatting milliseconds since UNIX_EPOCH as ISO 8601, in UTC.
+fn format_timestamp(timestamp: u64) -> String {
+    let seconds = (timestamp / 1000) as i64;
+    let nanoseconds = (timestamp % 1000) as u32 * 1_000_000;
+    Utc.timestamp(seconds, nanoseconds).format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
@@ -1,42 +1,56 @@
 //! Module containig all functionality needed for logging of process variables.
+//! Logs are stored by a Storage, see the storage module.
 
-use std::time::SystemTime;
-use std::fs;
-use std::fs::File;
+use std::time::{Duration, Instant, SystemTime};
 use std::io;
 
 use chrono::prelude::*;
 
-/// Function for getting a logfile.
-pub fn get_log(name: String) -> io::Result<File> {
-    File::open(format!("logs/{}", name))
-}
+use controller::ReferenceSeries;
+use controller::pid::PidParameters;
+use storage::{Storage, LogWriter, Name, MAX_NAME_LENGTH};
 
-/// Function for getting a list of all stored logs.
-pub fn get_list_of_logs() -> Vec<String> {
-    let mut result = Vec::new();
-    for file in fs::read_dir("logs").expect("Unable to read log folder") {
-        result.push(file.expect("Fail while reading log folder")
-                    .file_name()
-                    .into_string()
-                    .expect("Logname not a valid string"));
-    }
-    result
-}
+pub mod query;
+use self::query::{LogQuery, LogSelection};
+
+pub mod export;
+use self::export::Format;
+
+pub mod stats;
+
+pub mod retention;
 
-/// Function for deleting a log.
-pub fn delete_log(name: String) -> io::Result<()> {
-    fs::remove_file(format!("logs/{}", name))
+/// How often a Logger syncs the log to disk.
+const SYNC_PERIOD: Duration = Duration::from_secs(10);
+
+/// Function for getting the part of a log selected by the query.
+pub fn query_log(storage: &Storage, name: &Name, query: &LogQuery) -> io::Result<LogSelection> {
+    Ok(storage.get_log(name, query)?.select(query))
 }
 
+/// Function for exporting the part of a log selected by the query, in the given format.
+pub fn export_log(storage: &Storage, name: &Name, query: &LogQuery, format: Format)
+                  -> io::Result<String> {
+    Ok(export::export(&query_log(storage, name, query)?, format))
+}
 
+/// Function returning the current time as milliseconds since UNIX_EPOCH.
+fn get_timestamp() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
 
 /// Structure representing a log. It has a field storing the name of the
-/// reference series used, and a vector of LogEntrys.
+/// reference series used, a vector of LogEntrys, a vector of the changes
+/// made to the progress of the process and a vector of events.
 #[derive(Serialize, Deserialize)]
 pub struct Log {
     reference: String,
-    entries: Vec<LogEntry>
+    entries: Vec<LogEntry>,
+    #[serde(default)]
+    step_changes: Vec<StepChangeEntry>,
+    #[serde(default)]
+    events: Vec<EventEntry>,
 }
 
 impl Log {
@@ -44,13 +58,33 @@ impl Log {
     pub fn new(reference: &String) -> Log {
         Log {
             reference: reference.clone(),
-            entries: Vec::new()
+            entries: Vec::new(),
+            step_changes: Vec::new(),
+            events: Vec::new(),
         }
     }
 
     pub fn add_entry(&mut self, entry: LogEntry) {
         self.entries.push(entry);
     }
+
+    pub fn add_step_change(&mut self, step_change: StepChangeEntry) {
+        self.step_changes.push(step_change);
+    }
+
+    pub fn add_event(&mut self, event: EventEntry) {
+        self.events.push(event);
+    }
+
+    /// Returns the name of the reference series used.
+    pub fn get_reference(&self) -> &String {
+        &self.reference
+    }
+
+    /// Returns true if the log has no entries, step changes or events.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.step_changes.is_empty() && self.events.is_empty()
+    }
 }
 
 /// A single entry in a Log. Stores a timestamp with millisecond precision
@@ -68,69 +102,313 @@ pub struct LogEntry {
 
 impl LogEntry {
     pub fn new(reference: f32, input: f32, output: f32) -> LogEntry {
-        let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-        let timestamp = since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64;
         LogEntry {
             reference,
             input,
             output,
-            timestamp,
+            timestamp: get_timestamp(),
+        }
+    }
+
+    /// Makes a LogEntry for the given time, as number of milliseconds since UNIX_EPOCH.
+    pub fn with_timestamp(timestamp: u64, reference: f32, input: f32, output: f32) -> LogEntry {
+        LogEntry { timestamp, reference, input, output }
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn get_reference(&self) -> f32 {
+        self.reference
+    }
+
+    pub fn get_input(&self) -> f32 {
+        self.input
+    }
+
+    pub fn get_output(&self) -> f32 {
+        self.output
+    }
+}
+
+/// A change to the progress of a process.
+/// Step numbers are indices into the reference series used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StepChange {
+    /// The process jumped from one step to another.
+    Jump { from: usize, to: usize },
+    /// The rest of the step was skipped. The process continued with the next
+    /// step, or finished if it was the last one.
+    Skip { step: usize },
+    /// The duration of a step was extended by the given number of seconds.
+    Extend { step: usize, seconds: u64 },
+    /// The reference of a "hold until reached" step was reached, after waiting
+    /// the given number of seconds.
+    Reached { step: usize, seconds: u64 },
+    /// The reference of a "hold until reached" step was not reached before
+    /// the timeout, and the step timer was started anyway.
+    HoldTimeout { step: usize },
+}
+
+/// A StepChange, and the time it was made (as number of milliseconds since UNIX_EPOCH).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepChangeEntry {
+    timestamp: u64,
+    change: StepChange,
+}
+
+impl StepChangeEntry {
+    pub fn new(change: StepChange) -> StepChangeEntry {
+        StepChangeEntry {
+            change,
+            timestamp: get_timestamp(),
         }
     }
+
+    /// Makes a StepChangeEntry for a change made at the given time, as number
+    /// of milliseconds since UNIX_EPOCH.
+    pub fn with_timestamp(change: StepChange, timestamp: u64) -> StepChangeEntry {
+        StepChangeEntry { change, timestamp }
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn get_change(&self) -> &StepChange {
+        &self.change
+    }
 }
 
-/// Struct providing functionality to make a log.
-#[derive(Debug)]
+/// Something that happened during a process, written automatically by the
+/// Controller, or a note written by the brewer.
+/// Step numbers are indices into the reference series used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    /// A step was started. temp is the reference of the step, in degrees Celsius.
+    StepStarted { step: usize, temp: f32 },
+    Paused,
+    Resumed,
+    /// The process was stopped before following all of its references.
+    Stopped,
+    /// The process has followed all of its references.
+    Finished,
+    /// The process was stopped due to an error.
+    Faulted { message: String },
+    /// The sensor failed to give a temperature. The last temperature read is
+    /// used until the sensor recovers, or the process is faulted.
+    SensorFailed { message: String },
+    /// A note written by the brewer, such as "Grain added".
+    Note { text: String },
+}
+
+/// An Event, and the time it happened (as number of milliseconds since UNIX_EPOCH).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventEntry {
+    timestamp: u64,
+    event: Event,
+}
+
+impl EventEntry {
+    pub fn new(event: Event) -> EventEntry {
+        EventEntry {
+            event,
+            timestamp: get_timestamp(),
+        }
+    }
+
+    /// Makes an EventEntry for an event that happened at the given time, as
+    /// number of milliseconds since UNIX_EPOCH.
+    pub fn with_timestamp(event: Event, timestamp: u64) -> EventEntry {
+        EventEntry { event, timestamp }
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn get_event(&self) -> &Event {
+        &self.event
+    }
+}
+
+/// Information about a log, stored in the first line of the logfile. All
+/// fields except reference are None for logs written by earlier versions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogMetadata {
+    /// The name of the reference series used.
+    pub reference: String,
+    /// The name of the resource the process was run on.
+    pub resource: Option<String>,
+    pub process: Option<ProcessInfo>,
+    /// Milliseconds since UNIX_EPOCH
+    pub start_time: Option<u64>,
+    /// Milliseconds since UNIX_EPOCH. None while the process is running, and
+    /// if the server stopped before the process ended.
+    pub end_time: Option<u64>,
+}
+
+impl LogMetadata {
+    /// Makes metadata with only the name of the reference series used.
+    pub fn new(reference: &String) -> LogMetadata {
+        LogMetadata {
+            reference: reference.clone(),
+            resource: None,
+            process: None,
+            start_time: None,
+            end_time: None,
+        }
+    }
+}
+
+/// Description of how a process was run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessInfo {
+    /// The reference series as it was when the process was started.
+    pub reference_series: ReferenceSeries,
+    pub pid_parameters: PidParameters,
+    /// The frequency of the controller, in Hz.
+    pub frequency: u64,
+    /// The type of the sensor, as given by Sensor::get_type.
+    pub sensor: String,
+    /// The type of the output, as given by Output::get_type.
+    pub output: String,
+}
+
+/// Struct providing functionality to make a log. Records are synced to disk
+/// every SYNC_PERIOD, when a step change or event is recorded and when the
+/// Logger is dropped. Dropping the Logger marks the end of the process.
 pub struct Logger {
     last_entry: Option<LogEntry>,
-    name: String,
+    name: Name,
+    writer: Box<LogWriter>,
+    last_sync: Instant,
 }
 
 impl Logger {
-    // TODO: Make not panic if logfile already exists
+    /// Constructor for Logger. This function makes a new log with the name
+    /// %r-%s-%Y-%m-%dT%H%M%S-%n, where %r is the name of the reference series
+    /// used, %s is the name of the resource, %Y-%m-%dT%H%M%S is the local time
+    /// on ISO 8601 basic format, and %n is a sequence number. The sequence number
+    /// starts at 1, and is increased until a name not already used is found, so
+    /// an existing log is never overwritten. Long reference and resource names
+    /// are shortened. The metadata of the log is written immediately.
+    pub fn new(storage: &Storage, reference: &Name, resource: &Name, process: ProcessInfo)
+               -> io::Result<Logger> {
+        let time = Local::now();
+        let time = time.format("%Y-%m-%dT%H%M%S").to_string();
 
-    /// Constructor for Logger. This function makes a new log with the name %r-%Y-%m-%d,
-    /// where %r is the name of the reference series used, %Y-%m-%d is the date on ISO 8601
-    /// format. Note that is the fiel already exists this function panics.
-    pub fn new(reference: String) -> Logger {
-        // TODO: Make file
-        let log = Log::new(&reference);
+        // Leave room for the time and a sequence number. Names that don't fit
+        // are shortened, but the reference keeps at least half of the room.
+        let room = MAX_NAME_LENGTH.saturating_sub(time.len() + 8);
+        let resource_room = room - reference.as_str().len().min(room / 2);
+        let resource_part: String = resource.as_str().chars().take(resource_room).collect();
+        let prefix: String = reference.as_str().chars().take(room - resource_part.len()).collect();
 
-        let date = Local::now();
-        let date = date.format("%Y-%m-%d");
+        let metadata = LogMetadata {
+            reference: reference.to_string(),
+            resource: Some(resource.to_string()),
+            process: Some(process),
+            start_time: Some(get_timestamp()),
+            end_time: None,
+        };
 
-        let name = format!("logs/{}-{}", reference, date);
+        let mut sequence = 1;
+        loop {
+            let name = Name::new(&format!("{}-{}-{}-{}", prefix, resource_part, time, sequence))?;
+            match storage.create_log(&name, &metadata) {
+                Ok(writer) => return Ok(Logger {
+                    name,
+                    writer,
+                    last_sync: Instant::now(),
+                    last_entry: None,
+                }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => sequence += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-        fs::write(&name, serde_json::to_string(&log).expect("Unable to make JSON"))
-            .expect(&format!("Unable to write logfile {}", name));
+    /// Records an entry in the log. The entry is returned by get_last_entry
+    /// even if it could not be written.
+    pub fn add_entry(&mut self, reference: f32, input: f32, output: f32) -> io::Result<()> {
+        let entry = LogEntry::new(reference, input, output);
+        self.last_entry = Some(entry.clone());
+        self.writer.write_entry(&entry)?;
 
-        Logger {
-            name,
-            last_entry: None,
+        if self.last_sync.elapsed() >= SYNC_PERIOD {
+            self.sync()?;
         }
+        Ok(())
     }
 
-    pub fn add_entry(&mut self, reference: f32, input: f32, output: f32) {
-        let tmp_str = fs::read_to_string(&self.name)
-            .expect(&format!("Unable to open logfile: {}", self.name));
-        let mut log: Log = serde_json::from_str(
-            &fs::read_to_string(&self.name)
-                .expect(&format!("Unable to open logfile: {}", self.name))
-        ).expect(&format!("Invalid JSON in logfile: {}", tmp_str)); // We wrote this file, and it should be valid JSON
-        let entry = LogEntry::new(reference, input, output);
+    /// Records a change to the progress of the process in the log.
+    pub fn add_step_change(&mut self, change: StepChange) -> io::Result<()> {
+        self.writer.write_step_change(&StepChangeEntry::new(change))?;
+        self.sync()
+    }
 
-        log.add_entry(entry.clone());
+    /// Records an event in the log.
+    pub fn add_event(&mut self, event: EventEntry) -> io::Result<()> {
+        self.writer.write_event(&event)?;
+        self.sync()
+    }
 
-        fs::write(&self.name, serde_json::to_string(&log).unwrap())
-            .expect(&format!("Unable to write logfile {}", self.name));
-        self.last_entry = Some(entry);
+    /// Helper function making sure everything written is stored.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.sync()?;
+        self.last_sync = Instant::now();
+        Ok(())
     }
 
     pub fn get_last_entry(&self) -> Option<LogEntry> {
         self.last_entry.clone()
     }
 
-    pub fn get_name(&self) -> String {
+    pub fn get_name(&self) -> Name {
         self.name.clone()
     }
 }
+
+impl Drop for Logger {
+    fn drop(&mut self) {
+        let _ = self.writer.end(get_timestamp());
+        let _ = self.writer.sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use storage::file::FileStorage;
+
+    fn process_info() -> ProcessInfo {
+        ProcessInfo {
+            reference_series: ReferenceSeries::new(Vec::new()),
+            pid_parameters: PidParameters::default(),
+            frequency: 1,
+            sensor: "mock".to_owned(),
+            output: "mock".to_owned(),
+        }
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        let path = ::std::env::temp_dir().join(format!("brew-logger-{}", ::std::process::id()));
+        let storage = FileStorage::open(&path).unwrap();
+        let long = Name::new(&"x".repeat(MAX_NAME_LENGTH)).unwrap();
+        let short = Name::new("ipa").unwrap();
+        for &(reference, resource) in &[(&long, &short), (&short, &long), (&long, &long)] {
+            let logger = Logger::new(&storage, reference, resource, process_info()).unwrap();
+            let name = logger.get_name().as_str().to_owned();
+            assert!(name.starts_with(&reference.as_str()[..3]), "{}", name);
+            drop(logger);
+            let metadata = storage.get_log_metadata(&Name::new(&name).unwrap()).unwrap();
+            assert_eq!(metadata.resource, Some(resource.to_string()));
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
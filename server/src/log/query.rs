@@ -0,0 +1,232 @@
+//! Module containing queries for parts of a log, so that clients don't have to
+//! fetch every entry of a long process.
+
+use std::io;
+use std::str::FromStr;
+
+use super::{Log, LogEntry, StepChangeEntry, EventEntry};
+
+/// The values of a LogEntry that can be selected by a LogQuery.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Reference,
+    Input,
+    Output,
+}
+
+impl Field {
+    /// Returns the name of the field, as used in queries and encoded entries.
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            Field::Reference => "reference",
+            Field::Input => "input",
+            Field::Output => "output",
+        }
+    }
+}
+
+/// Every field, in the order they are encoded.
+pub const ALL_FIELDS: [Field; 3] = [Field::Reference, Field::Input, Field::Output];
+
+impl FromStr for Field {
+    type Err = io::Error;
+
+    fn from_str(field: &str) -> io::Result<Field> {
+        match ALL_FIELDS.iter().find( |f| f.get_name() == field.trim() ) {
+            Some(field) => Ok(*field),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("Unknown field: {}", field))),
+        }
+    }
+}
+
+/// A query for a part of a log. The default query selects the whole log.
+#[derive(Debug, Clone, Default)]
+pub struct LogQuery {
+    /// Only entries, step changes and events with a timestamp at or after from are selected.
+    /// Milliseconds since UNIX_EPOCH.
+    pub from: Option<u64>,
+    /// Only entries, step changes and events with a timestamp at or before to are selected.
+    /// Milliseconds since UNIX_EPOCH.
+    pub to: Option<u64>,
+    /// The maximum number of entries to select. If there are more entries,
+    /// they are split into max_points buckets of consecutive entries, and
+    /// each bucket is replaced by its mean.
+    pub max_points: Option<usize>,
+    /// The values to select. All values are selected if None.
+    pub fields: Option<Vec<Field>>,
+}
+
+impl LogQuery {
+    /// Parses a comma separated list of fields, such as "input,output".
+    pub fn parse_fields(fields: &str) -> io::Result<Vec<Field>> {
+        fields.split(',').map( |field| field.parse() ).collect()
+    }
+
+    /// Returns true if the timestamp is within the range of the query.
+    pub fn contains(&self, timestamp: u64) -> bool {
+        self.from.map_or(true, |from| timestamp >= from)
+            && self.to.map_or(true, |to| timestamp <= to)
+    }
+
+    /// Returns true if the field is selected by the query.
+    fn selects(&self, field: Field) -> bool {
+        match self.fields {
+            Some(ref fields) => fields.contains(&field),
+            None => true,
+        }
+    }
+}
+
+/// The part of a Log selected by a LogQuery. Encoded like a Log, but values
+/// that are not selected are left out of the entries.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogSelection {
+    reference: String,
+    entries: Vec<SelectedEntry>,
+    step_changes: Vec<StepChangeEntry>,
+    events: Vec<EventEntry>,
+    /// The selected fields, in the order they are encoded.
+    #[serde(skip)]
+    fields: Vec<Field>,
+}
+
+impl LogSelection {
+    pub fn get_entries(&self) -> &[SelectedEntry] {
+        &self.entries
+    }
+
+    pub fn get_step_changes(&self) -> &[StepChangeEntry] {
+        &self.step_changes
+    }
+
+    pub fn get_events(&self) -> &[EventEntry] {
+        &self.events
+    }
+
+    pub fn get_fields(&self) -> &[Field] {
+        &self.fields
+    }
+}
+
+/// A LogEntry, or the mean of a bucket of LogEntrys, with only the selected values.
+#[derive(Debug, Clone, Serialize)]
+pub struct SelectedEntry {
+    /// Milliseconds since UNIX_EPOCH
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<f32>,
+}
+
+impl SelectedEntry {
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Returns the value of the field, if it is selected.
+    pub fn get(&self, field: Field) -> Option<f32> {
+        match field {
+            Field::Reference => self.reference,
+            Field::Input => self.input,
+            Field::Output => self.output,
+        }
+    }
+}
+
+impl Log {
+    /// Removes the entries, step changes and events outside the time range of the query.
+    pub fn retain_range(&mut self, query: &LogQuery) {
+        self.entries.retain( |entry| query.contains(entry.timestamp) );
+        self.step_changes.retain( |step_change| query.contains(step_change.timestamp) );
+        self.events.retain( |event| query.contains(event.timestamp) );
+    }
+
+    /// Selects the part of the log given by the query.
+    pub fn select(&self, query: &LogQuery) -> LogSelection {
+        let entries: Vec<&LogEntry> = self.entries.iter()
+            .filter( |entry| query.contains(entry.timestamp) )
+            .collect();
+
+        let bucket_size = match query.max_points {
+            // Rounded up, without overflowing for large max_points
+            Some(max_points) if max_points > 0 =>
+                entries.len() / max_points + (entries.len() % max_points != 0) as usize,
+            _ => 1,
+        };
+        let entries = match query.max_points {
+            Some(0) => Vec::new(),
+            _ => entries.chunks(bucket_size.max(1))
+                .map( |bucket| select_bucket(bucket, query) )
+                .collect(),
+        };
+
+        LogSelection {
+            entries,
+            fields: ALL_FIELDS.iter().cloned().filter( |field| query.selects(*field) ).collect(),
+            reference: self.reference.clone(),
+            step_changes: self.step_changes.iter()
+                .filter( |step_change| query.contains(step_change.timestamp) )
+                .cloned()
+                .collect(),
+            events: self.events.iter()
+                .filter( |event| query.contains(event.timestamp) )
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Helper function replacing a bucket of entries by their mean. The timestamp
+/// is the middle of the bucket.
+fn select_bucket(bucket: &[&LogEntry], query: &LogQuery) -> SelectedEntry {
+    let first = bucket[0].timestamp;
+    let last = bucket[bucket.len() - 1].timestamp;
+    let mean = |value: fn(&LogEntry) -> f32| {
+        bucket.iter().map( |entry| value(entry) ).sum::<f32>() / bucket.len() as f32
+    };
+    let select = |field, value: fn(&LogEntry) -> f32| {
+        if query.selects(field) { Some(mean(value)) } else { None }
+    };
+
+    SelectedEntry {
+        timestamp: first + (last - first) / 2,
+        reference: select(Field::Reference, |entry| entry.reference),
+        input: select(Field::Input, |entry| entry.input),
+        output: select(Field::Output, |entry| entry.output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with_entries(count: u64) -> Log {
+        let mut log = Log::new(&"ipa".to_owned());
+        for timestamp in 0..count {
+            log.add_entry(LogEntry::with_timestamp(timestamp, 66.0, timestamp as f32, 0.0));
+        }
+        log
+    }
+
+    #[test]
+    fn limits_number_of_points() {
+        let log = log_with_entries(10);
+        for &(max_points, selected) in &[(1, 1), (3, 3), (4, 4), (5, 5), (10, 10), (11, 10)] {
+            let query = LogQuery { max_points: Some(max_points), ..LogQuery::default() };
+            assert_eq!(log.select(&query).get_entries().len(), selected, "max_points {}", max_points);
+        }
+        let query = LogQuery { max_points: Some(0), ..LogQuery::default() };
+        assert!(log.select(&query).get_entries().is_empty());
+    }
+
+    #[test]
+    fn accepts_large_number_of_points() {
+        let log = log_with_entries(10);
+        let query = LogQuery { max_points: Some(usize::max_value()), ..LogQuery::default() };
+        assert_eq!(log.select(&query).get_entries().len(), 10);
+    }
+}
@@ -0,0 +1,231 @@
+//! Module containing the retention policy of logs, so that logs don't fill the
+//! disk. Logs of processes that have ended can be compressed, and old logs are
+//! deleted or archived when they are older than a number of days, or when all
+//! logs together use more than a given number of bytes. The log of a running
+//! process is never touched.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use storage::{Storage, Name};
+use super::{Log, LogMetadata, get_timestamp};
+use super::query::LogQuery;
+
+/// How often the retention policy is applied by the thread started by spawn.
+pub const RETENTION_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+const MILLISECONDS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// What to do with logs of processes that have ended.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Compress logs when their process has ended.
+    pub compress: bool,
+    /// Remove logs whose process ended more than max_age_days days ago.
+    pub max_age_days: Option<u64>,
+    /// Remove the oldest logs until all logs together use at most this many bytes.
+    /// The logs of running processes are counted, but never removed.
+    pub max_total_size: Option<u64>,
+    /// Logs are removed by moving them to this directory, as gzip compressed
+    /// JSON, instead of deleting them.
+    pub archive: Option<PathBuf>,
+}
+
+/// Logs are left as they are. Compression must be enabled in the configuration,
+/// so that a new version never rewrites existing logs on its own.
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy {
+            compress: false,
+            max_age_days: None,
+            max_total_size: None,
+            archive: None,
+        }
+    }
+}
+
+/// The logs changed when a retention policy was applied.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RetentionReport {
+    pub compressed: Vec<Name>,
+    pub deleted: Vec<Name>,
+    pub archived: Vec<Name>,
+    /// Logs the policy could not be applied to, and why. They are left as
+    /// they are, and the next pass tries again.
+    pub skipped: Vec<(Name, String)>,
+}
+
+/// A log as written to the archive.
+#[derive(Serialize)]
+struct ArchivedLog {
+    metadata: LogMetadata,
+    log: Log,
+}
+
+/// A log the retention policy can be applied to.
+struct Candidate {
+    name: Name,
+    /// When the process ended, in milliseconds since UNIX_EPOCH.
+    ended: u64,
+}
+
+/// Applies the retention policy to every log in storage, except the logs of
+/// running processes. get_active_logs must return the names of the logs of
+/// every running process. It is called after the logs are listed, so a log
+/// made in the meantime is never touched. Logs that can not be read, compressed
+/// or removed are skipped, and listed in the report, so that one bad log does
+/// not stop the pass.
+pub fn apply<F>(storage: &Storage, policy: &RetentionPolicy, get_active_logs: F)
+                -> io::Result<RetentionReport>
+    where F: Fn() -> Vec<Name>
+{
+    let mut report = RetentionReport::default();
+    let names = storage.get_list_of_logs()?;
+    let active = get_active_logs();
+
+    let mut candidates = Vec::new();
+    let mut total_size: u64 = 0;
+    for name in names {
+        match storage.get_log_size(&name) {
+            Ok(size) => total_size += size,
+            Err(e) => {
+                report.skipped.push((name, e.to_string()));
+                continue;
+            },
+        }
+        if !active.contains(&name) {
+            match get_end_time(storage, &name) {
+                Ok(ended) => candidates.push(Candidate { name, ended }),
+                Err(e) => report.skipped.push((name, e.to_string())),
+            }
+        }
+    }
+    candidates.sort_by_key( |candidate| candidate.ended ); // Oldest first
+
+    if policy.compress {
+        let mut compressed = Vec::new();
+        for candidate in candidates {
+            match compress_log(storage, &candidate.name) {
+                Ok((size, compressed_size)) => {
+                    if compressed_size != size {
+                        total_size = total_size.saturating_sub(size).saturating_add(compressed_size);
+                        report.compressed.push(candidate.name.clone());
+                    }
+                    compressed.push(candidate);
+                },
+                Err(e) => report.skipped.push((candidate.name, e.to_string())),
+            }
+        }
+        candidates = compressed;
+    }
+
+    let now = get_timestamp();
+    for candidate in candidates {
+        let too_old = policy.max_age_days.map_or(false, |days| {
+            now.saturating_sub(candidate.ended) > days * MILLISECONDS_PER_DAY
+        });
+        let too_big = policy.max_total_size.map_or(false, |max| total_size > max);
+        if !too_old && !too_big {
+            continue;
+        }
+
+        let removed = storage.get_log_size(&candidate.name).and_then( |size| {
+            match policy.archive {
+                Some(ref directory) => archive_log(storage, &candidate.name, directory),
+                None => storage.delete_log(&candidate.name),
+            }.map( |_| size )
+        });
+        match removed {
+            Ok(size) => {
+                total_size = total_size.saturating_sub(size);
+                match policy.archive {
+                    Some(_) => report.archived.push(candidate.name),
+                    None => report.deleted.push(candidate.name),
+                }
+            },
+            Err(e) => report.skipped.push((candidate.name, e.to_string())),
+        }
+    }
+    Ok(report)
+}
+
+/// Starts a thread applying the retention policy every RETENTION_PERIOD.
+/// get_active_logs is used as in apply.
+pub fn spawn<F>(storage: Arc<Storage>, policy: RetentionPolicy, get_active_logs: F)
+    where F: 'static + Send + Fn() -> Vec<Name>
+{
+    thread::spawn(move || {
+        loop {
+            match apply(&*storage, &policy, &get_active_logs) {
+                Ok(report) => {
+                    for name in &report.compressed {
+                        println!("Compressed log {}", name);
+                    }
+                    for name in &report.archived {
+                        println!("Archived log {}", name);
+                    }
+                    for name in &report.deleted {
+                        println!("Deleted log {}", name);
+                    }
+                    for &(ref name, ref error) in &report.skipped {
+                        println!("Skipped log {}: {}", name, error);
+                    }
+                },
+                Err(e) => println!("Unable to apply retention policy: {}", e),
+            }
+            thread::sleep(RETENTION_PERIOD);
+        }
+    });
+}
+
+/// Helper function returning when the process of a log ended, in milliseconds
+/// since UNIX_EPOCH. If the end was not recorded, because the log was written
+/// by an earlier version or the server stopped during the process, the time of
+/// the last record is used.
+fn get_end_time(storage: &Storage, name: &Name) -> io::Result<u64> {
+    let metadata = storage.get_log_metadata(name)?;
+    if let Some(end_time) = metadata.end_time {
+        return Ok(end_time);
+    }
+    let log = storage.get_log(name, &LogQuery::default())?;
+    let last = log.entries.iter().map( |entry| entry.timestamp )
+        .chain(log.step_changes.iter().map( |step_change| step_change.timestamp ))
+        .chain(log.events.iter().map( |event| event.timestamp ))
+        .max();
+    Ok(last.or(metadata.start_time).unwrap_or(0))
+}
+
+/// Helper function compressing a log, returning its size before and after.
+fn compress_log(storage: &Storage, name: &Name) -> io::Result<(u64, u64)> {
+    let size = storage.get_log_size(name)?;
+    storage.compress_log(name)?;
+    Ok((size, storage.get_log_size(name)?))
+}
+
+/// Helper function moving a log to the archive directory, as the file
+/// <name>.json.gz. Fails with AlreadyExists if the log is already archived.
+fn archive_log(storage: &Storage, name: &Name, directory: &Path) -> io::Result<()> {
+    let archived = ArchivedLog {
+        metadata: storage.get_log_metadata(name)?,
+        log: storage.get_log(name, &LogQuery::default())?,
+    };
+    let json = serde_json::to_vec(&archived).expect("Unable to make JSON");
+
+    fs::create_dir_all(directory)?;
+    let path = directory.join(format!("{}.json.gz", name));
+    let file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder.write_all(&json)?;
+    encoder.finish()?.sync_all()?;
+
+    storage.delete_log(name)
+}
@@ -0,0 +1,182 @@
+//! Module containing analysis of how well a process followed its references.
+//! A log is split into segments at every reference change, and each segment is
+//! analysed like the step response of the controller. A ramp and the steady
+//! reference following it belong to the same segment.
+
+use super::{Log, LogEntry};
+
+/// The default band around the reference the input must stay within to be
+/// considered settled, in degrees.
+pub const DEFAULT_SETTLING_BAND: f32 = 0.5;
+
+/// The output of a controller when fully on.
+const MAX_OUTPUT: f32 = 100.0;
+
+/// Statistics of a whole log. All times are in seconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogStats {
+    pub segments: Vec<SegmentStats>,
+    pub duration: f32,
+    /// Integral of the absolute error, in degree seconds.
+    pub iae: f32,
+    /// Integral of the squared error, in degree² seconds.
+    pub ise: f32,
+    /// Time spent with the output fully on.
+    pub time_at_max_output: f32,
+    /// Estimate of the energy used, as the number of seconds the output would
+    /// have been fully on to use the same energy. Multiply by the power of the
+    /// heater to get the energy in joules.
+    pub full_output_seconds: f32,
+}
+
+/// Statistics of a segment of a log with a single reference. All times are in
+/// seconds, and relative to the start of the segment unless otherwise noted.
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentStats {
+    /// Milliseconds since UNIX_EPOCH
+    pub start: u64,
+    /// Milliseconds since UNIX_EPOCH
+    pub end: u64,
+    /// The reference of the segment. For a ramp, the reference at its end.
+    pub reference: f32,
+    /// The input at the start of the segment.
+    pub initial_input: f32,
+    /// Time for the input to go from 10% to 90% of the way to the reference.
+    /// None if it never got there, or if the input started within the settling band.
+    pub rise_time: Option<f32>,
+    /// How far the input went past the reference, in degrees.
+    pub overshoot: f32,
+    /// Time until the input stayed within the settling band of the reference.
+    /// None if it never settled.
+    pub settling_time: Option<f32>,
+    /// Mean of reference - input over the last 10% of the segment.
+    pub steady_state_error: f32,
+    /// Integral of the absolute error, in degree seconds.
+    pub iae: f32,
+    /// Integral of the squared error, in degree² seconds.
+    pub ise: f32,
+    pub time_at_max_output: f32,
+    pub full_output_seconds: f32,
+}
+
+/// Analyses a log. settling_band is the band around the reference, in degrees,
+/// the input must stay within to be considered settled.
+pub fn analyse(log: &Log, settling_band: f32) -> LogStats {
+    let entries = &log.entries;
+    // Each entry is held until the next one
+    let durations: Vec<f32> = entries.iter().zip(entries.iter().skip(1))
+        .map( |(entry, next)| seconds_between(entry, next) )
+        .chain(Some(0.0))
+        .take(entries.len())
+        .collect();
+
+    let segments: Vec<SegmentStats> = split(entries).into_iter()
+        .map( |(start, end)| analyse_segment(&entries[start..end], &durations[start..end],
+                                             settling_band) )
+        .collect();
+
+    LogStats {
+        duration: durations.iter().sum(),
+        iae: segments.iter().map( |segment| segment.iae ).sum(),
+        ise: segments.iter().map( |segment| segment.ise ).sum(),
+        time_at_max_output: segments.iter().map( |segment| segment.time_at_max_output ).sum(),
+        full_output_seconds: segments.iter().map( |segment| segment.full_output_seconds ).sum(),
+        segments,
+    }
+}
+
+/// Helper function returning the number of seconds from one entry to another.
+fn seconds_between(from: &LogEntry, to: &LogEntry) -> f32 {
+    to.timestamp.saturating_sub(from.timestamp) as f32 / 1000.0
+}
+
+/// Helper function splitting the entries into segments, returned as ranges of
+/// indices. A segment starts when the reference changes after being steady,
+/// so a ramp, where the reference changes every entry, is not split.
+fn split(entries: &[LogEntry]) -> Vec<(usize, usize)> {
+    let changed = |i: usize| i > 0 && (entries[i].reference - entries[i - 1].reference).abs() > 1e-6;
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for i in 1..entries.len() {
+        if changed(i) && !changed(i - 1) {
+            segments.push((start, i));
+            start = i;
+        }
+    }
+    if start < entries.len() {
+        segments.push((start, entries.len()));
+    }
+    segments
+}
+
+/// Helper function analysing a single segment. durations is the time each
+/// entry is held.
+fn analyse_segment(entries: &[LogEntry], durations: &[f32], settling_band: f32) -> SegmentStats {
+    let first = &entries[0];
+    let last = &entries[entries.len() - 1];
+    let reference = last.reference;
+    let initial_input = first.input;
+    let time = |entry: &LogEntry| seconds_between(first, entry);
+
+    let change = reference - initial_input;
+    let direction = change.signum();
+    let is_step = change.abs() > settling_band;
+    // Time the input first got the given fraction of the way to the reference
+    let time_to = |fraction: f32| entries.iter()
+        .find( |entry| (entry.input - initial_input) * direction >= fraction * change.abs() )
+        .map( |entry| time(entry) );
+
+    let rise_time = match (time_to(0.1), time_to(0.9)) {
+        (Some(start), Some(end)) if is_step => Some(end - start),
+        _ => None,
+    };
+    let overshoot = if is_step {
+        entries.iter()
+            .map( |entry| (entry.input - reference) * direction )
+            .fold(0.0, f32::max)
+    } else {
+        0.0
+    };
+    let settling_time = match entries.iter().rposition( |entry| (entry.input - reference).abs() > settling_band ) {
+        None => Some(0.0),
+        Some(i) if i + 1 < entries.len() => Some(time(&entries[i + 1])),
+        Some(_) => None,
+    };
+
+    let steady_since = time(last) * 0.9;
+    let steady: Vec<f32> = entries.iter()
+        .filter( |entry| time(entry) >= steady_since )
+        .map( |entry| entry.reference - entry.input )
+        .collect();
+    let steady_state_error = steady.iter().sum::<f32>() / steady.len() as f32;
+
+    let mut iae = 0.0;
+    let mut ise = 0.0;
+    let mut time_at_max_output = 0.0;
+    let mut full_output_seconds = 0.0;
+    for (entry, &duration) in entries.iter().zip(durations) {
+        let error = entry.reference - entry.input;
+        iae += error.abs() * duration;
+        ise += error * error * duration;
+        if entry.output >= MAX_OUTPUT {
+            time_at_max_output += duration;
+        }
+        full_output_seconds += entry.output.max(0.0).min(MAX_OUTPUT) / MAX_OUTPUT * duration;
+    }
+
+    SegmentStats {
+        start: first.timestamp,
+        end: last.timestamp,
+        reference,
+        initial_input,
+        rise_time,
+        overshoot,
+        settling_time,
+        steady_state_error,
+        iae,
+        ise,
+        time_at_max_output,
+        full_output_seconds,
+    }
+}
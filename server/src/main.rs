@@ -6,174 +6,342 @@ extern crate rocket_contrib;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
+extern crate toml;
 
 extern crate chrono;
 
+extern crate flate2;
+
 extern crate linux_embedded_hal;
 extern crate embedded_hal;
 extern crate sysfs_gpio;
 
-use std::collections::HashMap;
-use std::sync::Mutex;
-// use std::f32;
+#[macro_use] extern crate clap;
 
-pub mod controller;
-use controller::Controller;
-use controller::sensor::Sensor;
-use controller::output::Output;
-use controller::mock::*;
+#[cfg(feature = "sqlite")]
+#[macro_use] extern crate rusqlite;
 
-use controller::ds18b20;
-use controller::led;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, PoisonError};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
-use controller::pid::{Reference, PidParameters};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+pub mod controller;
+use controller::Controller;
+use controller::calibration::load_calibrations;
+use controller::mock::{MockInternalState, MockTemperatureSensor, MockOutput};
+use controller::pid::PidParameters;
+use controller::status::ControllerState;
 use controller::ReferenceSeries;
 
 pub mod log;
+use log::retention::RetentionPolicy;
+
+pub mod storage;
+use storage::{Storage, Name};
 
 pub mod interface;
 
-/// Program entry point
-fn main() {
-    log();
+pub mod config;
+use config::{Config, SensorConfig};
+
+/// The frequency used by simulate when no resource is given.
+const SIMULATION_FREQUENCY: u64 = 60;
+
+/// Function describing the command line interface.
+fn build_cli() -> App<'static, 'static> {
+    App::new("rusty-brew")
+        .about("Temperature controller for brewing")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("PATH")
+             .help("The config file. Defaults to $BREW_CONFIG, or brew.toml if it exists"))
+        .arg(Arg::with_name("data-dir")
+             .long("data-dir")
+             .value_name("PATH")
+             .help("The directory logs and reference series are stored in. \
+                    Defaults to $BREW_DATA_DIR, the config file, or the current directory"))
+        .subcommand(SubCommand::with_name("serve")
+                    .about("Runs the HTTP interface, controlling the configured resources"))
+        .subcommand(SubCommand::with_name("simulate")
+                    .about("Follows a reference series with a simulated process, and logs it")
+                    .arg(Arg::with_name("SERIES")
+                         .help("The name of a stored reference series")
+                         .required_unless("file"))
+                    .arg(Arg::with_name("file")
+                         .long("file")
+                         .value_name("PATH")
+                         .conflicts_with("SERIES")
+                         .help("Reads the reference series from a JSON file instead"))
+                    .arg(Arg::with_name("resource")
+                         .long("resource")
+                         .value_name("NAME")
+                         .help("Uses the PID parameters, frequency and limits of a configured resource"))
+                    .arg(Arg::with_name("interval")
+                         .long("interval")
+                         .value_name("SECONDS")
+                         .default_value("10")
+                         .validator(validate_seconds)
+                         .help("How often the status is printed")))
+        .subcommand(SubCommand::with_name("log-sensor")
+                    .about("Logs the sensor of a resource until Enter is pressed, \
+                            such as to capture a step response")
+                    .arg(Arg::with_name("RESOURCE")
+                         .help("A configured resource. A simulated sensor is used if not given"))
+                    .arg(Arg::with_name("interval")
+                         .long("interval")
+                         .value_name("SECONDS")
+                         .default_value("10")
+                         .validator(validate_seconds)
+                         .help("The time between each reading"))
+                    .arg(Arg::with_name("name")
+                         .long("name")
+                         .value_name("NAME")
+                         .default_value("step_response")
+                         .help("Used in the name of the log")))
+        .subcommand(SubCommand::with_name("probe")
+                    .about("Reads the sensor of every configured resource once"))
+        .subcommand(SubCommand::with_name("validate-config")
+                    .about("Checks the config file, without touching any hardware"))
 }
 
-/// Function for testing the controller
-fn test_pid() {
-    let environment = MockInternalState::new();
-    let sensor = MockTemperatureSensor::new(environment.clone());
-    let output = MockOutput::new(environment.clone());
+/// Helper function used by clap to check that an argument is a positive number of seconds.
+fn validate_seconds(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(()),
+        _ => Err("must be a positive number of seconds".to_owned()),
+    }
+}
 
-    let parameters = controller::pid::PidParameters::default();
-    let mut controller = Controller::new(sensor, output, parameters, 60);
+/// Function printing an error and exiting.
+fn exit_with_error<E: Display>(error: E) -> ! {
+    eprintln!("{}", error);
+    process::exit(1)
+}
 
-    let reference_series = vec![
-        Reference{duration: 60, temp: 55},
-        Reference{duration: 30, temp: 60},
-        Reference{duration: 30, temp: 75},
-    ];
-    let reference_series = ReferenceSeries::new(reference_series);
+/// Program entry point
+/// The data directory is given by --data-dir, the environment variable
+/// BREW_DATA_DIR or the config file, in that order, and is created if it
+/// doesn't exist. The config file is given by --config or BREW_CONFIG, and
+/// brew.toml is used if it exists.
+fn main() {
+    let matches = build_cli().get_matches();
+    let config = Config::load(matches.value_of_os("config").map(PathBuf::from))
+        .unwrap_or_else( |e| exit_with_error(e) );
+    let data_directory = config.get_data_directory(matches.value_of_os("data-dir").map(PathBuf::from));
 
-    controller.start("foo".to_owned(), reference_series).unwrap();
-    std::thread::sleep(std::time::Duration::from_secs(200));
+    let result = match matches.subcommand() {
+        ("serve", Some(_)) => serve(&config, &data_directory),
+        ("simulate", Some(arguments)) => simulate(&config, &data_directory, arguments),
+        ("log-sensor", Some(arguments)) => log_sensor(&config, &data_directory, arguments),
+        ("probe", Some(_)) => probe(&config, &data_directory),
+        ("validate-config", Some(_)) => validate_config(&config),
+        _ => unreachable!("A subcommand is required"),
+    };
+    if let Err(e) = result {
+        exit_with_error(e);
+    }
 }
 
-/// Function for testing HW sensor and output
-fn test_physical() {
-    let sensor1 = ds18b20::DS18B20::new("28-000009eab19f".to_owned());
-    let sensor2 = ds18b20::DS18B20::new("28-000009eb40fe".to_owned());
-    let mut output = led::LedOutput::new(20, 21, 50);
-    for _ in 0..10 {
-        output.set(30.0);
-        println!("Temp1: {}", sensor1.read());
-        println!("Temp2: {}", sensor2.read());
-        output.set(0.0);
+/// serve: Runs the HTTP interface, with the resources of the config file.
+fn serve(config: &Config, data_directory: &Path) -> io::Result<()> {
+    let storage = storage::open(data_directory)?;
+    let calibrations = load_calibrations(&*storage)?;
+    let controllers = config.build_resources(&calibrations)?;
+    if controllers.is_empty() {
+        println!("No resources are configured");
     }
-    output.set(100.0);
+    let controllers = Arc::new(controllers);
+
+    start_retention(&storage, &controllers, config.get_retention_policy(data_directory));
+    interface::init_interface(controllers, storage, config.sensors.clone(), calibrations);
+    Ok(())
 }
 
-/// Function for testing the server, using mocked input and output
-fn test_start_interface() {
-    let mock_state = MockInternalState::new();
-    let mock_sensor = MockTemperatureSensor::new(mock_state.clone());
-    let mock_output = MockOutput::new(mock_state.clone());
-    let mock_controller = Controller::new(mock_sensor,
-                                           mock_output,
-                                           PidParameters::new(7.0, 0.6, 0.0, 35.0),
-                                           60);
-
-    let reference = Reference { duration: 23, temp: 59 };
-    let series = ReferenceSeries::new(vec![reference]);
-    println!("{}", serde_json::to_string(&series).unwrap());
-    let mut controllers = HashMap::new();
-    controllers.insert("Mock".to_owned(), Mutex::new(mock_controller));
-
-    interface::init_interface(controllers);
+/// Function starting a thread applying the retention policy to the logs in
+/// storage. The logs of the processes running on the controllers are never touched.
+fn start_retention(storage: &Arc<Storage>, controllers: &interface::ResourceMap,
+                   policy: RetentionPolicy) {
+    let controllers = Arc::clone(controllers);
+    log::retention::spawn(Arc::clone(storage), policy, move || {
+        controllers.values()
+            .filter_map( |controller| {
+                controller.lock().unwrap_or_else(PoisonError::into_inner).get_name_of_current_process()
+            })
+            .collect()
+    });
 }
 
-/// Function for testing mocked input and output
-fn test_mock_system() {
-    {
-        let mock_state = MockInternalState::new();
-        let mock_sensor = MockTemperatureSensor::new(mock_state.clone());
-        let mut mock_output = MockOutput::new(mock_state.clone());
-        println!("Sensor value: {}", mock_sensor.read());
-        mock_output.set(100_f32);
-        for _ in 0..999 {
-            {
-                println!("Sensor value: {}", mock_sensor.read());
-            }
-            std::thread::sleep(std::time::Duration::from_millis(1000));
+/// simulate: Follows a reference series with a mock sensor and output, in real
+/// time. The process is logged like any other process, and the status is
+/// printed until it has finished.
+fn simulate(config: &Config, data_directory: &Path, arguments: &ArgMatches) -> io::Result<()> {
+    let storage = storage::open(data_directory)?;
+    let (reference_name, reference_series) = match arguments.value_of_os("file") {
+        Some(path) => read_reference_series(Path::new(path))?,
+        None => {
+            let name = Name::new(arguments.value_of("SERIES").expect("SERIES is required"))?;
+            let reference_series = storage.get_reference_series(&name)?;
+            (name, reference_series)
+        },
+    };
+
+    let mock_state = MockInternalState::new();
+    let sensor = MockTemperatureSensor::new(mock_state.clone());
+    let output = MockOutput::new(mock_state.clone());
+    let mut controller = match arguments.value_of("resource") {
+        Some(name) => {
+            config.validate()?;
+            let resource = config.resources.get(name).ok_or_else( || {
+                io::Error::new(io::ErrorKind::NotFound, format!("No resource called {}", name))
+            })?;
+            let mut controller = Controller::new(sensor, output, resource.pid.clone(),
+                                                 resource.frequency);
+            controller.set_limits(resource.limits.clone());
+            controller.set_failure_policy(resource.sensor_failure.clone());
+            controller
+        },
+        None => Controller::new(sensor, output, PidParameters::default(), SIMULATION_FREQUENCY),
+    };
+
+    let resource = Name::new(arguments.value_of("resource").unwrap_or("simulation"))?;
+    let log_name = controller.start(&*storage, &resource, reference_name, reference_series)?;
+    println!("Logging to {}", log_name);
+
+    let interval = Duration::from_secs(value_t!(arguments, "interval", u64).unwrap_or_else( |e| e.exit() ));
+    // The state of the process changes when the controller has cleaned up
+    loop {
+        thread::sleep(interval);
+        let status = controller.get_status();
+        match status.state {
+            ControllerState::Running | ControllerState::Paused => {},
+            _ => break,
         }
-        // let mock_controller = controller::Controller::new(mock_sensor, mock_output);
+        let input = controller.get_last_log_entry().map( |entry| entry.get_input() );
+        println!("{:?}: step {}, reference {}, temperature {}, {} s remaining",
+                 status.state,
+                 status.step.map_or("-".to_owned(), |step| (step + 1).to_string()),
+                 format_temperature(status.reference),
+                 format_temperature(input),
+                 status.total_remaining);
+    }
 
-        // let controllers = vec![mock_controller];
-        let _mock_controller = Controller::new(mock_sensor,
-                                               mock_output,
-                                               PidParameters::default(),
-                                               60);
+    match controller.get_status().state {
+        ControllerState::Faulted => Err(io::Error::new(io::ErrorKind::Other, "The process faulted")),
+        state => {
+            println!("{:?}", state);
+            Ok(())
+        },
     }
-    std::thread::sleep(std::time::Duration::from_millis(10*1000));
-    println!("Finished");
 }
 
-/// Function for testing if we can get a list of logs
-fn test_get_logs() {
-    for name in log::get_list_of_logs() {
-        println!("{}", name);
-    }
+/// Helper function reading a reference series from a JSON file. The reference
+/// series is named after the file.
+fn read_reference_series(path: &Path) -> io::Result<(Name, ReferenceSeries)> {
+    let contents = fs::read_to_string(path).map_err( |e| {
+        io::Error::new(e.kind(), format!("Unable to read {}: {}", path.display(), e))
+    })?;
+    let reference_series = serde_json::from_str(&contents).map_err( |e| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("Invalid reference series {}: {}", path.display(), e))
+    })?;
+    let name = Name::new(&path.file_stem().unwrap_or_default().to_string_lossy())?;
+    Ok((name, reference_series))
 }
 
-fn test_log() {
-    let sensor = ds18b20::DS18B20::new("28-000006739a01".to_owned());
-    let mock_state = MockInternalState::new();
-    let mock_output = MockOutput::new(mock_state.clone());
-    let reference_series = vec![
-        Reference{duration: 60*60*24, temp: 100},
-    ];
-    let reference_series = ReferenceSeries::new(reference_series);
-    let parameters = controller::pid::PidParameters::default();
-
-    let mut controller = controller::Controller::new(sensor, mock_output, parameters, 1);
-    controller.start("response".to_owned(), reference_series).expect("Unable to start logging");
+/// Helper function formatting a temperature, which is "-" if it is unknown.
+fn format_temperature(temperature: Option<f32>) -> String {
+    temperature.map_or("-".to_owned(), |temperature| format!("{:.1}", temperature))
 }
 
-fn log() {
-    use std::sync::mpsc::channel;
-    use std::thread;
-    use std::time::Duration;
-    use std::io;
+/// log-sensor: Logs the sensor of a resource, with nothing controlling it, until
+/// Enter is pressed. Used to capture the step response of a process, by
+/// changing the output by hand after logging has started.
+fn log_sensor(config: &Config, data_directory: &Path, arguments: &ArgMatches) -> io::Result<()> {
+    let storage = storage::open(data_directory)?;
+    let calibrations = load_calibrations(&*storage)?;
+    let (resource, sensor_config) = match arguments.value_of("RESOURCE") {
+        Some(name) => {
+            config.validate()?;
+            let resource = config.resources.get(name).ok_or_else( || {
+                io::Error::new(io::ErrorKind::NotFound, format!("No resource called {}", name))
+            })?;
+            (Name::new(name)?, &resource.sensor)
+        },
+        None => (Name::new("mock")?, &SensorConfig::Mock),
+    };
+    let sensor = sensor_config.build(&config.sensors, &calibrations, &MockInternalState::new());
+    let name = Name::new(arguments.value_of("name").expect("name has a default value"))?;
+    let interval = Duration::from_secs(value_t!(arguments, "interval", u64).unwrap_or_else( |e| e.exit() ));
 
-    // let sensor = ds18b20::DS18B20::new("28-000006739a01".to_owned());
-    let sensor = MockTemperatureSensor::new(MockInternalState::new());
-    let mut log = log::Logger::new("step_response".to_owned());
-    let (tx, rx) = channel();
+    let process = log::ProcessInfo {
+        reference_series: ReferenceSeries::new(Vec::new()),
+        pid_parameters: PidParameters::default(),
+        frequency: 0,
+        sensor: sensor.get_type(),
+        output: "None".to_owned(),
+    };
+    let mut log = log::Logger::new(&*storage, &name, &resource, process)?;
+    let (tx, rx) = channel::<()>();
 
+    // The log is ended when the thread returns
     let handle = thread::spawn(move || {
         loop {
-            match rx.try_recv() {
-                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                    return;
-                }
-                _ => {
-                    println!("Logged");
-                    log.add_entry(0., sensor.read(), 0.);
-                    thread::sleep(Duration::from_secs(10));
-                }
+            match sensor.read() {
+                Ok(temperature) => {
+                    println!("Temperature: {}", temperature);
+                    if let Err(e) = log.add_entry(0., temperature, 0.) {
+                        println!("Unable to write log {}: {}", log.get_name(), e);
+                    }
+                },
+                Err(e) => println!("Unable to read sensor: {}", e),
             }
-        }
-    });
-    thread::spawn(move || {
-        loop {
-            let mut input = String::new();
-            print!("Stop logging? ");
-            io::stdin().read_line(&mut input).expect("Failed to read input");
-            if input.trim() == "y" {
-                return;
-            } else {
-                tx.send(()).expect("Unable to send");
+            match rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {},
+                _ => return,
             }
         }
     });
-    handle.join().expect("Error while joining");
+
+    println!("Logging {}. Press Enter to stop.", resource);
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    drop(tx);
+    handle.join().expect("Logging thread panicked");
+    Ok(())
+}
+
+/// probe: Reads the sensor of every configured resource once, corrected by its
+/// calibration. The outputs are not touched.
+fn probe(config: &Config, data_directory: &Path) -> io::Result<()> {
+    config.validate()?;
+    let calibrations = load_calibrations(&*storage::open(data_directory)?)?;
+    if config.resources.is_empty() {
+        println!("No resources are configured");
+    }
+    for (name, resource) in &config.resources {
+        let sensor = resource.sensor.build(&config.sensors, &calibrations, &MockInternalState::new());
+        match sensor.read() {
+            Ok(temperature) => println!("{}: {} ({})", name, temperature, sensor.get_type()),
+            Err(e) => println!("{}: {} ({})", name, e, sensor.get_type()),
+        }
+    }
+    Ok(())
+}
+
+/// validate-config: Checks that every configured resource can be built.
+fn validate_config(config: &Config) -> io::Result<()> {
+    config.validate()?;
+    let names: Vec<&str> = config.resources.keys().map( |name| name.as_str() ).collect();
+    println!("The config is valid. Resources: {}", names.join(", "));
+    Ok(())
 }
@@ -0,0 +1,313 @@
+//! Module containing a Storage keeping every log, reference series and
+//! calibration in its own file.
+//!
+//! Logs are stored as JSON Lines: the first line is a header with the metadata
+//! of the log, and every following line is a single entry, step change or event.
+//! The end of the process is marked by its own line. This lets a log be
+//! appended to, instead of rewriting all of it for every entry.
+//! Logs of processes that have ended can be compressed with gzip, and are then
+//! decompressed when read. Compressed logs keep their name, and are recognised
+//! by the gzip header.
+//! Reference series and calibrations are stored as JSON.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use controller::ReferenceSeries;
+use controller::calibration::Calibration;
+use log::{Log, LogEntry, LogMetadata, StepChangeEntry, EventEntry};
+use log::query::LogQuery;
+use super::{Directory, Name, Storage, LogWriter};
+
+/// A single line of a logfile.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Header(LogMetadata),
+    Entry(LogEntry),
+    StepChange(StepChangeEntry),
+    Event(EventEntry),
+    /// The process has ended. timestamp is in milliseconds since UNIX_EPOCH.
+    End { timestamp: u64 },
+}
+
+/// Helper function encoding a record as a line of a logfile.
+fn encode_record(record: &Record) -> Vec<u8> {
+    let mut line = serde_json::to_vec(record).expect("Unable to make JSON");
+    line.push(b'\n');
+    line
+}
+
+/// The first bytes of every gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Helper function compressing the contents of a logfile.
+fn compress(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+/// Storage keeping logs in the directory "logs", reference series in the
+/// directory "references", and calibrations in the directory "calibrations",
+/// of a given directory.
+pub struct FileStorage {
+    logs: Directory,
+    references: Directory,
+    calibrations: Directory,
+    /// Held while a logfile is rewritten, so that a note added at the same
+    /// time as the log is compressed is not lost.
+    rewrite_lock: Mutex<()>,
+}
+
+impl FileStorage {
+    /// Makes a FileStorage for the given directory. The directories used are
+    /// not created.
+    pub fn new<P: AsRef<Path>>(directory: P) -> FileStorage {
+        FileStorage {
+            logs: Directory::new(directory.as_ref().join("logs")),
+            references: Directory::new(directory.as_ref().join("references")),
+            calibrations: Directory::new(directory.as_ref().join("calibrations")),
+            rewrite_lock: Mutex::new(()),
+        }
+    }
+
+    /// Makes a FileStorage for the given directory, and creates the directories
+    /// used if they don't exist.
+    pub fn open<P: AsRef<Path>>(directory: P) -> io::Result<FileStorage> {
+        let storage = FileStorage::new(directory);
+        storage.logs.create_all()?;
+        storage.references.create_all()?;
+        storage.calibrations.create_all()?;
+        Ok(storage)
+    }
+
+    /// Returns true if the logfile is compressed.
+    fn is_compressed(&self, name: &Name) -> io::Result<bool> {
+        let mut magic = [0; 2];
+        let mut file = self.logs.open(name)?;
+        match file.read_exact(&mut magic) {
+            Ok(()) => Ok(magic == GZIP_MAGIC),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the contents of a logfile, decompressed if it is compressed.
+    fn read_log(&self, name: &Name) -> io::Result<String> {
+        let contents = self.logs.read(name)?;
+        if !contents.starts_with(&GZIP_MAGIC) {
+            return String::from_utf8(contents)
+                .map_err( |e| io::Error::new(io::ErrorKind::InvalidData, e) );
+        }
+        let mut decompressed = String::new();
+        GzDecoder::new(&contents[..]).read_to_string(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+impl Storage for FileStorage {
+    fn create_log(&self, name: &Name, metadata: &LogMetadata) -> io::Result<Box<LogWriter>> {
+        let mut writer = FileLogWriter { file: BufWriter::new(self.logs.create(name)?) };
+        writer.write(&Record::Header(metadata.clone()))?;
+        writer.sync()?;
+        Ok(Box::new(writer))
+    }
+
+    fn get_list_of_logs(&self) -> io::Result<Vec<Name>> {
+        self.logs.list()
+    }
+
+    /// Logs stored as a single JSON encoded Log, as done by earlier versions,
+    /// can also be read.
+    fn get_log(&self, name: &Name, query: &LogQuery) -> io::Result<Log> {
+        parse_log(&self.read_log(name)?, query)
+    }
+
+    /// Logs written by earlier versions only have the name of the reference series used.
+    fn get_log_metadata(&self, name: &Name) -> io::Result<LogMetadata> {
+        let contents = self.read_log(name)?;
+        let mut metadata = match parse_header(&contents) {
+            Some(metadata) => metadata,
+            None => return parse_log(&contents, &LogQuery::default())
+                .map( |log| LogMetadata::new(log.get_reference()) ),
+        };
+        // Notes can be added after the end of the process
+        metadata.end_time = contents.lines().rev()
+            .filter( |line| line.contains("\"end\"") ) // Don't decode every entry
+            .filter_map( |line| match serde_json::from_str(line) {
+                Ok(Record::End { timestamp }) => Some(timestamp),
+                _ => None,
+            })
+            .next();
+        Ok(metadata)
+    }
+
+    /// Events can not be added to logs written by earlier versions. A
+    /// compressed log is rewritten with the event added.
+    fn add_event(&self, name: &Name, event: &EventEntry) -> io::Result<()> {
+        let _guard = self.rewrite_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let old_version = || io::Error::new(io::ErrorKind::InvalidData,
+                                            "Events can not be added to logs written by earlier versions");
+        let line = encode_record(&Record::Event(event.clone()));
+
+        if self.is_compressed(name)? {
+            let contents = self.read_log(name)?;
+            if parse_header(&contents).is_none() {
+                return Err(old_version());
+            }
+            let mut contents = contents.into_bytes();
+            contents.extend(line);
+            return self.logs.replace(name, compress(&contents)?);
+        }
+
+        let mut header = String::new();
+        BufReader::new(self.logs.open(name)?).read_line(&mut header)?;
+        if parse_header(&header).is_none() {
+            return Err(old_version());
+        }
+        let mut file = self.logs.append(name)?;
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    fn delete_log(&self, name: &Name) -> io::Result<()> {
+        self.logs.remove(name)
+    }
+
+    fn get_log_size(&self, name: &Name) -> io::Result<u64> {
+        self.logs.size(name)
+    }
+
+    fn compress_log(&self, name: &Name) -> io::Result<()> {
+        let _guard = self.rewrite_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.is_compressed(name)? {
+            return Ok(());
+        }
+        let contents = self.logs.read(name)?;
+        self.logs.replace(name, compress(&contents)?)
+    }
+
+    fn get_list_of_reference_series(&self) -> io::Result<Vec<Name>> {
+        self.references.list()
+    }
+
+    fn get_reference_series(&self, name: &Name) -> io::Result<ReferenceSeries> {
+        serde_json::from_str(&self.references.read_to_string(name)?)
+            .map_err( |e| io::Error::new(io::ErrorKind::InvalidData, e) )
+    }
+
+    fn store_reference_series(&self, name: &Name, reference_series: &ReferenceSeries)
+                              -> io::Result<()> {
+        self.references.replace(name, serde_json::to_string(reference_series)
+                                .expect("Invalid reference series JSON"))
+    }
+
+    fn rename_reference_series(&self, name: &Name, new_name: &Name) -> io::Result<()> {
+        self.references.rename(name, new_name)
+    }
+
+    fn delete_reference_series(&self, name: &Name) -> io::Result<()> {
+        self.references.remove(name)
+    }
+
+    fn get_list_of_calibrations(&self) -> io::Result<Vec<Name>> {
+        self.calibrations.list()
+    }
+
+    fn get_calibration(&self, sensor: &Name) -> io::Result<Calibration> {
+        serde_json::from_str(&self.calibrations.read_to_string(sensor)?)
+            .map_err( |e| io::Error::new(io::ErrorKind::InvalidData, e) )
+    }
+
+    fn store_calibration(&self, sensor: &Name, calibration: &Calibration) -> io::Result<()> {
+        self.calibrations.replace(sensor, serde_json::to_string(calibration)
+                                  .expect("Invalid calibration JSON"))
+    }
+
+    fn delete_calibration(&self, sensor: &Name) -> io::Result<()> {
+        self.calibrations.remove(sensor)
+    }
+}
+
+/// Helper function decoding the header of a logfile. Returns None if the
+/// logfile was written by an earlier version, and has no header.
+fn parse_header(contents: &str) -> Option<LogMetadata> {
+    match contents.lines().next().map( |line| serde_json::from_str(line) ) {
+        Some(Ok(Record::Header(metadata))) => Some(metadata),
+        _ => None,
+    }
+}
+
+/// Helper function for decoding the contents of a logfile. Only the entries,
+/// step changes and events within the time range of the query are kept.
+fn parse_log(contents: &str, query: &LogQuery) -> io::Result<Log> {
+    let metadata = match parse_header(contents) {
+        Some(metadata) => metadata,
+        None => { // Written by an earlier version
+            let mut log: Log = serde_json::from_str(contents)
+                .map_err( |e| io::Error::new(io::ErrorKind::InvalidData, e) )?;
+            log.retain_range(query);
+            return Ok(log);
+        },
+    };
+
+    let mut log = Log::new(&metadata.reference);
+    for line in contents.lines().skip(1) {
+        match serde_json::from_str(line) {
+            Ok(Record::Entry(entry)) => if query.contains(entry.get_timestamp()) {
+                log.add_entry(entry)
+            },
+            Ok(Record::StepChange(step_change)) => if query.contains(step_change.get_timestamp()) {
+                log.add_step_change(step_change)
+            },
+            Ok(Record::Event(event)) => if query.contains(event.get_timestamp()) {
+                log.add_event(event)
+            },
+            Ok(Record::Header(_)) | Ok(Record::End { .. }) => {},
+            Err(_) => {}, // The last line is cut short if the server stopped while writing it
+        }
+    }
+    Ok(log)
+}
+
+/// LogWriter appending records to a logfile.
+struct FileLogWriter {
+    file: BufWriter<File>,
+}
+
+impl FileLogWriter {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        self.file.write_all(&encode_record(record))
+    }
+}
+
+impl LogWriter for FileLogWriter {
+    fn write_entry(&mut self, entry: &LogEntry) -> io::Result<()> {
+        self.write(&Record::Entry(entry.clone()))
+    }
+
+    fn write_step_change(&mut self, step_change: &StepChangeEntry) -> io::Result<()> {
+        self.write(&Record::StepChange(step_change.clone()))
+    }
+
+    fn write_event(&mut self, event: &EventEntry) -> io::Result<()> {
+        self.write(&Record::Event(event.clone()))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    fn end(&mut self, timestamp: u64) -> io::Result<()> {
+        self.write(&Record::End { timestamp })
+    }
+}
@@ -0,0 +1,433 @@
+//! Module containing the types used to store logs, reference series and the
+//! calibrations of sensors. They are stored by a Storage, which can either be a
+//! FileStorage, storing them as files, or a SqliteStorage, storing them in an
+//! SQLite database (only when built with the "sqlite" feature).
+//! Everything stored is addressed by a Name, which can never contain a path,
+//! so a Directory can never be used to reach files outside of it.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use controller::ReferenceSeries;
+use controller::calibration::Calibration;
+use log::{Log, LogEntry, LogMetadata, StepChangeEntry, EventEntry};
+use log::query::LogQuery;
+
+pub mod file;
+pub use self::file::FileStorage;
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStorage;
+
+/// The name of the database used by SqliteStorage in the data directory.
+#[cfg(feature = "sqlite")]
+pub const DATABASE_NAME: &str = "brew.sqlite";
+
+/// Function opening the storage in the given data directory, which is created
+/// if it doesn't exist. When built with the "sqlite" feature, everything is kept
+/// in the database DATABASE_NAME, and otherwise as files in the directories
+/// "logs", "references" and "calibrations".
+pub fn open<P: AsRef<Path>>(data_directory: P) -> io::Result<Arc<Storage>> {
+    create_directory(data_directory.as_ref())?;
+    #[cfg(feature = "sqlite")]
+    {
+        return Ok(Arc::new(SqliteStorage::open(data_directory.as_ref().join(DATABASE_NAME))?));
+    }
+    #[cfg(not(feature = "sqlite"))]
+    {
+        return Ok(Arc::new(FileStorage::open(data_directory)?));
+    }
+}
+
+/// Helper function creating a directory, and any missing parent directory.
+/// The error tells which directory could not be created.
+fn create_directory(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(path).map_err( |e| {
+        io::Error::new(e.kind(), format!("Unable to create directory {}: {}", path.display(), e))
+    })
+}
+
+/// A place to store logs, reference series and calibrations.
+pub trait Storage : Send + Sync {
+    /// Creates a new log, with the given metadata. Fails with AlreadyExists if
+    /// there already is a log with the given name.
+    fn create_log(&self, name: &Name, metadata: &LogMetadata) -> io::Result<Box<LogWriter>>;
+
+    fn get_list_of_logs(&self) -> io::Result<Vec<Name>>;
+
+    /// Returns the names of the logs with entries, step changes or events
+    /// within the time range of the query, such as every process run during a
+    /// week. The rest of the query is not used. Every log is read, unless the
+    /// storage can find them faster.
+    fn get_list_of_logs_in_range(&self, query: &LogQuery) -> io::Result<Vec<Name>> {
+        let mut names = Vec::new();
+        for name in self.get_list_of_logs()? {
+            if !self.get_log(&name, query)?.is_empty() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    /// Returns the entries, step changes and events of a log that are within
+    /// the time range of the query. The rest of the query is not used.
+    fn get_log(&self, name: &Name, query: &LogQuery) -> io::Result<Log>;
+
+    fn get_log_metadata(&self, name: &Name) -> io::Result<LogMetadata>;
+
+    /// Adds an event to a log. Must not be used for the log of a running
+    /// process, which is written by its LogWriter.
+    fn add_event(&self, name: &Name, event: &EventEntry) -> io::Result<()>;
+
+    fn delete_log(&self, name: &Name) -> io::Result<()>;
+
+    /// Returns the number of bytes used to store a log. May be an estimate.
+    fn get_log_size(&self, name: &Name) -> io::Result<u64>;
+
+    /// Compresses a log, which is still read like any other log. Must not be
+    /// used for the log of a running process. Does nothing if the log already
+    /// is compressed, or if the storage does not compress logs.
+    fn compress_log(&self, _name: &Name) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn get_list_of_reference_series(&self) -> io::Result<Vec<Name>>;
+
+    /// Returns a stored reference series. Fails with InvalidData if it can not
+    /// be decoded.
+    fn get_reference_series(&self, name: &Name) -> io::Result<ReferenceSeries>;
+
+    /// Stores a reference series, replacing any reference series with the same name.
+    fn store_reference_series(&self, name: &Name, reference_series: &ReferenceSeries)
+                              -> io::Result<()>;
+
+    /// Renames a stored reference series. Fails with AlreadyExists if a
+    /// reference series called new_name already exists.
+    fn rename_reference_series(&self, name: &Name, new_name: &Name) -> io::Result<()>;
+
+    fn delete_reference_series(&self, name: &Name) -> io::Result<()>;
+
+    /// Returns the ids of the sensors with a stored calibration.
+    fn get_list_of_calibrations(&self) -> io::Result<Vec<Name>>;
+
+    /// Returns the calibration of a sensor. Fails with InvalidData if it can
+    /// not be decoded.
+    fn get_calibration(&self, sensor: &Name) -> io::Result<Calibration>;
+
+    /// Stores the calibration of a sensor, replacing any earlier calibration.
+    fn store_calibration(&self, sensor: &Name, calibration: &Calibration) -> io::Result<()>;
+
+    fn delete_calibration(&self, sensor: &Name) -> io::Result<()>;
+}
+
+/// Trait implemented by the types used to write a single log, made by
+/// Storage::create_log. Records may be buffered until sync is called.
+pub trait LogWriter : Send {
+    fn write_entry(&mut self, entry: &LogEntry) -> io::Result<()>;
+
+    fn write_step_change(&mut self, step_change: &StepChangeEntry) -> io::Result<()>;
+
+    fn write_event(&mut self, event: &EventEntry) -> io::Result<()>;
+
+    /// Makes sure everything written is stored, so it is kept if the server stops.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Marks the end of the process, at the given time (as number of
+    /// milliseconds since UNIX_EPOCH).
+    fn end(&mut self, timestamp: u64) -> io::Result<()>;
+}
+
+/// The maximum number of characters in a Name.
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// A validated name of a stored file, such as a log or a reference series.
+/// Names are trimmed of surrounding whitespace, and can only contain ASCII
+/// letters, digits, '-', '_' and '.'. A name can not start with '.', and can
+/// not contain "..".
+///
+/// ```no_run
+/// assert!(Name::new("mash-66.5").is_ok());
+/// assert_eq!(Name::new("  ipa  ").unwrap().as_str(), "ipa");
+/// assert!(Name::new("").is_err());
+/// assert!(Name::new("..").is_err());
+/// assert!(Name::new(".hidden").is_err());
+/// assert!(Name::new("../etc/passwd").is_err());
+/// assert!(Name::new("logs/ipa").is_err());
+/// assert!(Name::new("..\\boot.ini").is_err());
+/// assert!(Name::new("/etc/passwd").is_err());
+/// assert!(Name::new("ipa\0").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Name(String);
+
+impl Name {
+    /// Makes a new Name, failing if the name is not valid.
+    pub fn new(name: &str) -> Result<Name, NameError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(NameError::Empty);
+        }
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(NameError::TooLong);
+        }
+        if let Some(c) = name.chars().find( |c| !is_valid_character(*c) ) {
+            return Err(NameError::InvalidCharacter(c));
+        }
+        if name.starts_with('.') || name.contains("..") {
+            return Err(NameError::InvalidDots);
+        }
+        Ok(Name(name.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Helper function deciding which characters can be used in a Name.
+fn is_valid_character(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The reasons a name can be rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NameError {
+    Empty,
+    /// The name is longer than MAX_NAME_LENGTH characters.
+    TooLong,
+    /// The name contains a character that is not allowed, such as a path separator.
+    InvalidCharacter(char),
+    /// The name starts with '.' or contains "..".
+    InvalidDots,
+}
+
+impl Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NameError::Empty => write!(f, "The name is empty"),
+            NameError::TooLong =>
+                write!(f, "The name is longer than {} characters", MAX_NAME_LENGTH),
+            NameError::InvalidCharacter(c) =>
+                write!(f, "The name contains the invalid character {:?}", c),
+            NameError::InvalidDots =>
+                write!(f, "The name starts with '.' or contains \"..\""),
+        }
+    }
+}
+
+impl From<NameError> for io::Error {
+    fn from(error: NameError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+    }
+}
+
+/// A directory of stored files. All files are addressed by Name, and symbolic
+/// links are never followed, so files outside the directory can not be reached.
+#[derive(Debug, Clone)]
+pub struct Directory {
+    path: PathBuf,
+}
+
+impl Directory {
+    /// Makes a new Directory for the given path. The directory is not created.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Directory {
+        Directory { path: path.into() }
+    }
+
+    /// Creates the directory, and any missing parent directory. The error
+    /// tells which directory could not be created.
+    pub fn create_all(&self) -> io::Result<()> {
+        create_directory(&self.path)
+    }
+
+    /// Returns the path of the file with the given name. Fails if the file
+    /// is a symbolic link.
+    pub fn get_path(&self, name: &Name) -> io::Result<PathBuf> {
+        let path = self.path.join(name.as_str());
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.file_type().is_symlink() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                          "Symbolic links are not allowed"));
+            }
+        }
+        Ok(path)
+    }
+
+    /// Returns the names of all files in the directory. Files that don't have
+    /// a valid name are skipped.
+    pub fn list(&self) -> io::Result<Vec<Name>> {
+        let mut result = Vec::new();
+        for file in fs::read_dir(&self.path)? {
+            if let Ok(file_name) = file?.file_name().into_string() {
+                match Name::new(&file_name) {
+                    Ok(ref name) if name.as_str() == file_name => result.push(name.clone()),
+                    _ => {}, // Not made by us
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn exists(&self, name: &Name) -> bool {
+        self.path.join(name.as_str()).exists()
+    }
+
+    pub fn open(&self, name: &Name) -> io::Result<File> {
+        File::open(self.get_path(name)?)
+    }
+
+    pub fn read(&self, name: &Name) -> io::Result<Vec<u8>> {
+        fs::read(self.get_path(name)?)
+    }
+
+    pub fn read_to_string(&self, name: &Name) -> io::Result<String> {
+        fs::read_to_string(self.get_path(name)?)
+    }
+
+    pub fn size(&self, name: &Name) -> io::Result<u64> {
+        Ok(fs::metadata(self.get_path(name)?)?.len())
+    }
+
+    /// Creates a new file. Fails with AlreadyExists if the file already exists,
+    /// so a file is never overwritten.
+    pub fn create(&self, name: &Name) -> io::Result<File> {
+        OpenOptions::new().write(true).create_new(true).open(self.get_path(name)?)
+    }
+
+    /// Opens an existing file for appending.
+    pub fn append(&self, name: &Name) -> io::Result<File> {
+        OpenOptions::new().append(true).open(self.get_path(name)?)
+    }
+
+    pub fn write<C: AsRef<[u8]>>(&self, name: &Name, contents: C) -> io::Result<()> {
+        fs::write(self.get_path(name)?, contents)
+    }
+
+    /// Replaces the contents of a file, or makes it if it doesn't exist. The
+    /// new contents are written to a hidden file first, which then replaces the
+    /// file, so the file is never left partly written.
+    pub fn replace<C: AsRef<[u8]>>(&self, name: &Name, contents: C) -> io::Result<()> {
+        let path = self.get_path(name)?;
+        let temporary = self.path.join(format!(".{}.tmp", name));
+        let mut file = File::create(&temporary)?;
+        file.write_all(contents.as_ref())?;
+        file.sync_data()?;
+        fs::rename(temporary, path)
+    }
+
+    pub fn remove(&self, name: &Name) -> io::Result<()> {
+        fs::remove_file(self.get_path(name)?)
+    }
+
+    /// Renames a file. Fails if a file called new_name already exists.
+    pub fn rename(&self, name: &Name, new_name: &Name) -> io::Result<()> {
+        if self.exists(new_name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} already exists", new_name)));
+        }
+        fs::rename(self.get_path(name)?, self.get_path(new_name)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_names() {
+        assert_eq!(Name::new("mash-66.5").unwrap().as_str(), "mash-66.5");
+        assert_eq!(Name::new("  ipa_2  ").unwrap().as_str(), "ipa_2");
+        assert!(Name::new("a.b").is_ok());
+    }
+
+    #[test]
+    fn rejects_paths() {
+        assert_eq!(Name::new(".."), Err(NameError::InvalidDots));
+        assert_eq!(Name::new("a..b"), Err(NameError::InvalidDots));
+        assert_eq!(Name::new(".hidden"), Err(NameError::InvalidDots));
+        assert_eq!(Name::new("logs/ipa"), Err(NameError::InvalidCharacter('/')));
+        assert_eq!(Name::new("/etc/passwd"), Err(NameError::InvalidCharacter('/')));
+        assert_eq!(Name::new("../etc/passwd"), Err(NameError::InvalidCharacter('/')));
+        assert_eq!(Name::new("..\\boot.ini"), Err(NameError::InvalidCharacter('\\')));
+        assert_eq!(Name::new("ipa\0"), Err(NameError::InvalidCharacter('\0')));
+        assert_eq!(Name::new(""), Err(NameError::Empty));
+        assert_eq!(Name::new("  "), Err(NameError::Empty));
+    }
+
+    #[test]
+    fn rejects_long_names() {
+        assert!(Name::new(&"x".repeat(MAX_NAME_LENGTH)).is_ok());
+        assert_eq!(Name::new(&"x".repeat(MAX_NAME_LENGTH + 1)), Err(NameError::TooLong));
+        // The length is counted in characters, and checked before the characters
+        assert_eq!(Name::new(&"æ".repeat(MAX_NAME_LENGTH)), Err(NameError::InvalidCharacter('æ')));
+        assert_eq!(Name::new(&"æ".repeat(MAX_NAME_LENGTH + 1)), Err(NameError::TooLong));
+    }
+
+    #[test]
+    fn finds_logs_in_range() {
+        let path = ::std::env::temp_dir().join(format!("brew-storage-{}", ::std::process::id()));
+        let storage = FileStorage::open(&path).unwrap();
+        for &(name, timestamp) in &[("a", 1000), ("b", 3000)] {
+            let name = Name::new(name).unwrap();
+            let mut writer = storage.create_log(&name, &LogMetadata::new(&"ipa".to_owned())).unwrap();
+            writer.write_entry(&LogEntry::with_timestamp(timestamp, 66.0, 64.5, 35.0)).unwrap();
+            writer.sync().unwrap();
+        }
+
+        let query = LogQuery { from: Some(2000), ..LogQuery::default() };
+        assert_eq!(storage.get_list_of_logs_in_range(&query).unwrap(), vec![Name::new("b").unwrap()]);
+        let query = LogQuery { to: Some(500), ..LogQuery::default() };
+        assert!(storage.get_list_of_logs_in_range(&query).unwrap().is_empty());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn replaces_files() {
+        let path = ::std::env::temp_dir().join(format!("brew-replace-{}", ::std::process::id()));
+        let directory = Directory::new(&path);
+        directory.create_all().unwrap();
+        let name = Name::new("ipa").unwrap();
+        directory.replace(&name, "first").unwrap();
+        directory.replace(&name, "second").unwrap();
+        assert_eq!(directory.read_to_string(&name).unwrap(), "second");
+        assert_eq!(directory.list().unwrap(), vec![name]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symbolic_links() {
+        let path = ::std::env::temp_dir().join(format!("brew-directory-{}", ::std::process::id()));
+        let directory = Directory::new(&path);
+        directory.create_all().unwrap();
+        let secret = path.join("secret");
+        fs::write(&secret, "secret").unwrap();
+        ::std::os::unix::fs::symlink(&secret, path.join("link")).unwrap();
+
+        let link = Name::new("link").unwrap();
+        assert_eq!(directory.get_path(&link).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(directory.read_to_string(&link).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(directory.write(&link, "overwritten").is_err());
+        assert!(directory.replace(&link, "overwritten").is_err());
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "secret");
+        assert_eq!(directory.read_to_string(&Name::new("secret").unwrap()).unwrap(), "secret");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
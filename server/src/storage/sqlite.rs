@@ -0,0 +1,548 @@
+//! Module containing a Storage keeping every log, reference series and
+//! calibration in a single SQLite database.
+//!
+//! Entries, step changes and events are stored in their own tables, indexed by
+//! log and timestamp, so a part of a long log can be read without reading all
+//! of it. Metadata, step changes, events, reference series and calibrations
+//! are stored as JSON.
+
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rusqlite::{Connection, OptionalExtension, NO_PARAMS};
+
+use controller::ReferenceSeries;
+use controller::calibration::Calibration;
+use log::{Log, LogEntry, LogMetadata, StepChangeEntry, EventEntry};
+use log::query::LogQuery;
+use super::{Name, Storage, LogWriter};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS logs (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        metadata TEXT NOT NULL,
+        end_time INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS entries (
+        log_id INTEGER NOT NULL REFERENCES logs(id) ON DELETE CASCADE,
+        timestamp INTEGER NOT NULL,
+        reference REAL NOT NULL,
+        input REAL NOT NULL,
+        output REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS entries_by_time ON entries(log_id, timestamp);
+    CREATE TABLE IF NOT EXISTS step_changes (
+        log_id INTEGER NOT NULL REFERENCES logs(id) ON DELETE CASCADE,
+        timestamp INTEGER NOT NULL,
+        change TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS step_changes_by_time ON step_changes(log_id, timestamp);
+    CREATE TABLE IF NOT EXISTS events (
+        log_id INTEGER NOT NULL REFERENCES logs(id) ON DELETE CASCADE,
+        timestamp INTEGER NOT NULL,
+        event TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_by_time ON events(log_id, timestamp);
+    CREATE TABLE IF NOT EXISTS reference_series (
+        name TEXT PRIMARY KEY,
+        content TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS calibrations (
+        sensor TEXT PRIMARY KEY,
+        content TEXT NOT NULL
+    );
+";
+
+/// Helper function converting an SQLite error to an io::Error.
+fn to_io_error(error: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+/// Helper function converting a JSON error to an io::Error. Only used for
+/// decoding, as stored values are always valid JSON.
+fn to_invalid_data(error: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Helper function returning the error used when nothing is stored with the given name.
+fn not_found(name: &Name) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", name))
+}
+
+/// Storage keeping everything in an SQLite database. Every log and reference
+/// series is shared through a single connection.
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at the given path, and creates it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SqliteStorage> {
+        let connection = Connection::open(path).map_err(to_io_error)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;").map_err(to_io_error)?;
+        connection.execute_batch(SCHEMA).map_err(to_io_error)?;
+        Ok(SqliteStorage { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    /// Helper function returning the id of the log with the given name.
+    fn get_log_id(connection: &Connection, name: &Name) -> io::Result<i64> {
+        connection.query_row("SELECT id FROM logs WHERE name = ?1", params![name.as_str()],
+                             |row| row.get(0))
+            .optional()
+            .map_err(to_io_error)?
+            .ok_or_else( || not_found(name) )
+    }
+}
+
+/// Helper function returning the time range of a query, as used in the queries below.
+/// Timestamps too large to be stored are clamped.
+fn get_range(query: &LogQuery) -> (i64, i64) {
+    let clamp = |timestamp: u64| timestamp.min(i64::max_value() as u64) as i64;
+    (query.from.map_or(0, &clamp),
+     query.to.map_or(i64::max_value(), &clamp))
+}
+
+impl Storage for SqliteStorage {
+    fn create_log(&self, name: &Name, metadata: &LogMetadata) -> io::Result<Box<LogWriter>> {
+        let log_id = {
+            let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+            if SqliteStorage::get_log_id(&connection, name).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("{} already exists", name)));
+            }
+            connection.execute("INSERT INTO logs (name, metadata) VALUES (?1, ?2)",
+                               params![name.as_str(), serde_json::to_string(metadata)
+                                       .expect("Unable to make JSON")])
+                .map_err(to_io_error)?;
+            connection.last_insert_rowid()
+        };
+        Ok(Box::new(SqliteLogWriter {
+            connection: Arc::clone(&self.connection),
+            log_id,
+            entries: Vec::new(),
+        }))
+    }
+
+    fn get_list_of_logs(&self) -> io::Result<Vec<Name>> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut statement = connection.prepare("SELECT name FROM logs ORDER BY name")
+            .map_err(to_io_error)?;
+        let names = statement.query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .map_err(to_io_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(to_io_error)?;
+        // Only valid names are stored
+        Ok(names.iter().filter_map( |name| Name::new(name).ok() ).collect())
+    }
+
+    /// Uses the indexes on timestamps, so the logs are not read.
+    fn get_list_of_logs_in_range(&self, query: &LogQuery) -> io::Result<Vec<Name>> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let (from, to) = get_range(query);
+        let mut statement = connection.prepare(
+            "SELECT name FROM logs WHERE
+                 EXISTS (SELECT 1 FROM entries
+                         WHERE log_id = logs.id AND timestamp BETWEEN ?1 AND ?2)
+              OR EXISTS (SELECT 1 FROM step_changes
+                         WHERE log_id = logs.id AND timestamp BETWEEN ?1 AND ?2)
+              OR EXISTS (SELECT 1 FROM events
+                         WHERE log_id = logs.id AND timestamp BETWEEN ?1 AND ?2)
+             ORDER BY name")
+            .map_err(to_io_error)?;
+        let names = statement.query_map(params![from, to], |row| row.get::<_, String>(0))
+            .map_err(to_io_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(to_io_error)?;
+        Ok(names.iter().filter_map( |name| Name::new(name).ok() ).collect())
+    }
+
+    fn get_log(&self, name: &Name, query: &LogQuery) -> io::Result<Log> {
+        let metadata = self.get_log_metadata(name)?;
+        let mut log = Log::new(&metadata.reference);
+
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let log_id = SqliteStorage::get_log_id(&connection, name)?;
+        let (from, to) = get_range(query);
+
+        let mut statement = connection.prepare(
+            "SELECT timestamp, reference, input, output FROM entries
+             WHERE log_id = ?1 AND timestamp BETWEEN ?2 AND ?3 ORDER BY timestamp, rowid")
+            .map_err(to_io_error)?;
+        let entries = statement.query_map(params![log_id, from, to], |row| {
+            Ok(LogEntry::with_timestamp(row.get::<_, i64>(0)? as u64,
+                                        row.get::<_, f64>(1)? as f32,
+                                        row.get::<_, f64>(2)? as f32,
+                                        row.get::<_, f64>(3)? as f32))
+        }).map_err(to_io_error)?;
+        for entry in entries {
+            log.add_entry(entry.map_err(to_io_error)?);
+        }
+
+        let mut statement = connection.prepare(
+            "SELECT change FROM step_changes
+             WHERE log_id = ?1 AND timestamp BETWEEN ?2 AND ?3 ORDER BY timestamp, rowid")
+            .map_err(to_io_error)?;
+        let step_changes = statement.query_map(params![log_id, from, to],
+                                               |row| row.get::<_, String>(0))
+            .map_err(to_io_error)?;
+        for step_change in step_changes {
+            log.add_step_change(serde_json::from_str(&step_change.map_err(to_io_error)?)
+                                .map_err(to_invalid_data)?);
+        }
+
+        let mut statement = connection.prepare(
+            "SELECT event FROM events
+             WHERE log_id = ?1 AND timestamp BETWEEN ?2 AND ?3 ORDER BY timestamp, rowid")
+            .map_err(to_io_error)?;
+        let events = statement.query_map(params![log_id, from, to], |row| row.get::<_, String>(0))
+            .map_err(to_io_error)?;
+        for event in events {
+            log.add_event(serde_json::from_str(&event.map_err(to_io_error)?)
+                          .map_err(to_invalid_data)?);
+        }
+        Ok(log)
+    }
+
+    fn get_log_metadata(&self, name: &Name) -> io::Result<LogMetadata> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let (metadata, end_time) = connection.query_row(
+            "SELECT metadata, end_time FROM logs WHERE name = ?1", params![name.as_str()],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?)))
+            .optional()
+            .map_err(to_io_error)?
+            .ok_or_else( || not_found(name) )?;
+        let mut metadata: LogMetadata = serde_json::from_str(&metadata).map_err(to_invalid_data)?;
+        metadata.end_time = end_time.map( |end_time| end_time as u64 );
+        Ok(metadata)
+    }
+
+    fn add_event(&self, name: &Name, event: &EventEntry) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let log_id = SqliteStorage::get_log_id(&connection, name)?;
+        insert_event(&connection, log_id, event)
+    }
+
+    fn delete_log(&self, name: &Name) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let log_id = SqliteStorage::get_log_id(&connection, name)?;
+        // The entries, step changes and events are deleted with the log
+        connection.execute("DELETE FROM logs WHERE id = ?1", params![log_id])
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    /// Estimated from the size of the stored values, as SQLite doesn't keep
+    /// track of the space used by each log.
+    fn get_log_size(&self, name: &Name) -> io::Result<u64> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let log_id = SqliteStorage::get_log_id(&connection, name)?;
+        let size: i64 = connection.query_row(
+            "SELECT (SELECT length(metadata) FROM logs WHERE id = ?1)
+                  + (SELECT COUNT(*) * 40 FROM entries WHERE log_id = ?1)
+                  + (SELECT IFNULL(SUM(length(change) + 16), 0) FROM step_changes WHERE log_id = ?1)
+                  + (SELECT IFNULL(SUM(length(event) + 16), 0) FROM events WHERE log_id = ?1)",
+            params![log_id], |row| row.get(0))
+            .map_err(to_io_error)?;
+        Ok(size as u64)
+    }
+
+    fn get_list_of_reference_series(&self) -> io::Result<Vec<Name>> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut statement = connection.prepare("SELECT name FROM reference_series ORDER BY name")
+            .map_err(to_io_error)?;
+        let names = statement.query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .map_err(to_io_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(to_io_error)?;
+        Ok(names.iter().filter_map( |name| Name::new(name).ok() ).collect())
+    }
+
+    fn get_reference_series(&self, name: &Name) -> io::Result<ReferenceSeries> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let content: String = connection.query_row(
+            "SELECT content FROM reference_series WHERE name = ?1", params![name.as_str()],
+            |row| row.get(0))
+            .optional()
+            .map_err(to_io_error)?
+            .ok_or_else( || not_found(name) )?;
+        serde_json::from_str(&content).map_err(to_invalid_data)
+    }
+
+    fn store_reference_series(&self, name: &Name, reference_series: &ReferenceSeries)
+                              -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        connection.execute("INSERT OR REPLACE INTO reference_series (name, content) VALUES (?1, ?2)",
+                           params![name.as_str(), serde_json::to_string(reference_series)
+                                   .expect("Invalid reference series JSON")])
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    fn rename_reference_series(&self, name: &Name, new_name: &Name) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let exists = |name: &Name| connection.query_row(
+            "SELECT 1 FROM reference_series WHERE name = ?1", params![name.as_str()],
+            |row| row.get::<_, i64>(0))
+            .optional()
+            .map( |found| found.is_some() )
+            .map_err(to_io_error);
+        if !exists(name)? {
+            return Err(not_found(name));
+        }
+        if exists(new_name)? {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} already exists", new_name)));
+        }
+        connection.execute("UPDATE reference_series SET name = ?2 WHERE name = ?1",
+                           params![name.as_str(), new_name.as_str()])
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    fn delete_reference_series(&self, name: &Name) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let deleted = connection.execute("DELETE FROM reference_series WHERE name = ?1",
+                                         params![name.as_str()])
+            .map_err(to_io_error)?;
+        if deleted == 0 {
+            return Err(not_found(name));
+        }
+        Ok(())
+    }
+
+    fn get_list_of_calibrations(&self) -> io::Result<Vec<Name>> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut statement = connection.prepare("SELECT sensor FROM calibrations ORDER BY sensor")
+            .map_err(to_io_error)?;
+        let sensors = statement.query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .map_err(to_io_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(to_io_error)?;
+        Ok(sensors.iter().filter_map( |sensor| Name::new(sensor).ok() ).collect())
+    }
+
+    fn get_calibration(&self, sensor: &Name) -> io::Result<Calibration> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let content: String = connection.query_row(
+            "SELECT content FROM calibrations WHERE sensor = ?1", params![sensor.as_str()],
+            |row| row.get(0))
+            .optional()
+            .map_err(to_io_error)?
+            .ok_or_else( || not_found(sensor) )?;
+        serde_json::from_str(&content).map_err(to_invalid_data)
+    }
+
+    fn store_calibration(&self, sensor: &Name, calibration: &Calibration) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        connection.execute("INSERT OR REPLACE INTO calibrations (sensor, content) VALUES (?1, ?2)",
+                           params![sensor.as_str(), serde_json::to_string(calibration)
+                                   .expect("Invalid calibration JSON")])
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    fn delete_calibration(&self, sensor: &Name) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let deleted = connection.execute("DELETE FROM calibrations WHERE sensor = ?1",
+                                         params![sensor.as_str()])
+            .map_err(to_io_error)?;
+        if deleted == 0 {
+            return Err(not_found(sensor));
+        }
+        Ok(())
+    }
+}
+
+/// Helper function storing an event of the given log.
+fn insert_event(connection: &Connection, log_id: i64, event: &EventEntry) -> io::Result<()> {
+    connection.execute("INSERT INTO events (log_id, timestamp, event) VALUES (?1, ?2, ?3)",
+                       params![log_id, event.get_timestamp() as i64,
+                               serde_json::to_string(event).expect("Unable to make JSON")])
+        .map_err(to_io_error)?;
+    Ok(())
+}
+
+/// LogWriter storing a log in the database. Entries are kept in memory until
+/// the log is synced, and are then stored in a single transaction, as storing
+/// every entry on its own is slow. If the transaction fails, its entries are
+/// lost, so a failing database doesn't fill the memory.
+struct SqliteLogWriter {
+    connection: Arc<Mutex<Connection>>,
+    log_id: i64,
+    entries: Vec<LogEntry>,
+}
+
+impl LogWriter for SqliteLogWriter {
+    fn write_entry(&mut self, entry: &LogEntry) -> io::Result<()> {
+        self.entries.push(entry.clone());
+        Ok(())
+    }
+
+    fn write_step_change(&mut self, step_change: &StepChangeEntry) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        connection.execute("INSERT INTO step_changes (log_id, timestamp, change) VALUES (?1, ?2, ?3)",
+                           params![self.log_id, step_change.get_timestamp() as i64,
+                                   serde_json::to_string(step_change).expect("Unable to make JSON")])
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    fn write_event(&mut self, event: &EventEntry) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        insert_event(&connection, self.log_id, event)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+        let entries = mem::replace(&mut self.entries, Vec::new());
+        let mut connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let transaction = connection.transaction().map_err(to_io_error)?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO entries (log_id, timestamp, reference, input, output)
+                 VALUES (?1, ?2, ?3, ?4, ?5)")
+                .map_err(to_io_error)?;
+            for entry in &entries {
+                statement.execute(params![self.log_id, entry.get_timestamp() as i64,
+                                          entry.get_reference() as f64,
+                                          entry.get_input() as f64,
+                                          entry.get_output() as f64])
+                    .map_err(to_io_error)?;
+            }
+        }
+        transaction.commit().map_err(to_io_error)
+    }
+
+    fn end(&mut self, timestamp: u64) -> io::Result<()> {
+        let connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        connection.execute("UPDATE logs SET end_time = ?2 WHERE id = ?1",
+                           params![self.log_id, timestamp as i64])
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::pid::Reference;
+    use log::{Event, StepChange};
+
+    fn open() -> SqliteStorage {
+        SqliteStorage::open(":memory:").unwrap()
+    }
+
+    fn name(name: &str) -> Name {
+        Name::new(name).unwrap()
+    }
+
+    /// Helper function writing a log with entries at the given timestamps.
+    fn write_log(storage: &SqliteStorage, log: &str, timestamps: &[u64]) -> Box<LogWriter> {
+        let mut writer = storage.create_log(&name(log), &LogMetadata::new(&"ipa".to_owned())).unwrap();
+        for &timestamp in timestamps {
+            writer.write_entry(&LogEntry::with_timestamp(timestamp, 66.0, 64.5, 35.0)).unwrap();
+        }
+        writer.sync().unwrap();
+        writer
+    }
+
+    #[test]
+    fn stores_logs() {
+        let storage = open();
+        let mut writer = write_log(&storage, "a", &[3000, 1000, 2000]);
+        writer.write_step_change(&StepChangeEntry::with_timestamp(StepChange::Skip { step: 0 }, 1500))
+            .unwrap();
+        writer.write_event(&EventEntry::with_timestamp(Event::Paused, 2500)).unwrap();
+        writer.end(4000).unwrap();
+        storage.add_event(&name("a"), &EventEntry::with_timestamp(Event::Resumed, 3500)).unwrap();
+
+        let log = storage.get_log(&name("a"), &LogQuery::default()).unwrap().select(&LogQuery::default());
+        let timestamps: Vec<u64> = log.get_entries().iter().map( |entry| entry.get_timestamp() ).collect();
+        assert_eq!(timestamps, vec![1000, 2000, 3000]);
+        assert_eq!(log.get_step_changes().len(), 1);
+        assert_eq!(log.get_events().len(), 2);
+        assert_eq!(storage.get_log_metadata(&name("a")).unwrap().end_time, Some(4000));
+
+        assert!(storage.create_log(&name("a"), &LogMetadata::new(&"ipa".to_owned())).is_err());
+        storage.delete_log(&name("a")).unwrap();
+        assert_eq!(storage.get_log_metadata(&name("a")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(storage.get_list_of_logs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_time_range() {
+        let storage = open();
+        write_log(&storage, "a", &[1000, 2000, 3000]);
+
+        let query = LogQuery { from: Some(1500), to: Some(3000), ..LogQuery::default() };
+        let log = storage.get_log(&name("a"), &query).unwrap().select(&query);
+        assert_eq!(log.get_entries().len(), 2);
+
+        // Timestamps too large to be stored select everything after from
+        let query = LogQuery { from: Some(2000), to: Some(u64::max_value()), ..LogQuery::default() };
+        let log = storage.get_log(&name("a"), &query).unwrap().select(&query);
+        assert_eq!(log.get_entries().len(), 2);
+        let query = LogQuery { from: Some(u64::max_value()), ..LogQuery::default() };
+        assert!(storage.get_log(&name("a"), &query).unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_logs_in_range() {
+        let storage = open();
+        write_log(&storage, "a", &[1000, 2000]);
+        write_log(&storage, "b", &[3000, 4000]);
+        let mut writer = write_log(&storage, "c", &[]);
+        writer.write_event(&EventEntry::with_timestamp(Event::Note { text: "Hops".to_owned() }, 5000))
+            .unwrap();
+
+        let in_range = |from, to| {
+            let query = LogQuery { from, to, ..LogQuery::default() };
+            storage.get_list_of_logs_in_range(&query).unwrap()
+        };
+        assert_eq!(in_range(None, None), vec![name("a"), name("b"), name("c")]);
+        assert_eq!(in_range(Some(1500), Some(3500)), vec![name("a"), name("b")]);
+        assert_eq!(in_range(Some(2500), None), vec![name("b"), name("c")]);
+        assert_eq!(in_range(Some(4500), Some(u64::max_value())), vec![name("c")]);
+        assert!(in_range(Some(6000), None).is_empty());
+    }
+
+    #[test]
+    fn drops_entries_that_can_not_be_stored() {
+        let storage = open();
+        let mut writer = write_log(&storage, "a", &[]);
+        storage.delete_log(&name("a")).unwrap();
+
+        // The log no longer exists, so the entries can't be stored
+        writer.write_entry(&LogEntry::with_timestamp(1000, 66.0, 64.5, 35.0)).unwrap();
+        assert!(writer.sync().is_err());
+        assert!(writer.sync().is_ok());
+    }
+
+    #[test]
+    fn stores_reference_series_and_calibrations() {
+        let storage = open();
+        let series = ReferenceSeries::new(vec![Reference { duration: 60, temp: 66.0, hold: None, ramp: None }]);
+        storage.store_reference_series(&name("ipa"), &series).unwrap();
+        storage.store_reference_series(&name("stout"), &series).unwrap();
+        assert_eq!(storage.rename_reference_series(&name("ipa"), &name("stout")).unwrap_err().kind(),
+                   io::ErrorKind::AlreadyExists);
+        storage.rename_reference_series(&name("ipa"), &name("pale")).unwrap();
+        assert_eq!(storage.get_list_of_reference_series().unwrap(), vec![name("pale"), name("stout")]);
+        assert_eq!(storage.get_reference_series(&name("pale")).unwrap().references.len(), 1);
+        storage.delete_reference_series(&name("pale")).unwrap();
+        assert_eq!(storage.delete_reference_series(&name("pale")).unwrap_err().kind(),
+                   io::ErrorKind::NotFound);
+
+        let calibration = Calibration { offset: 0.5, ..Calibration::default() };
+        storage.store_calibration(&name("28-000009eab19f"), &calibration).unwrap();
+        assert_eq!(storage.get_calibration(&name("28-000009eab19f")).unwrap(), calibration);
+        assert_eq!(storage.get_list_of_calibrations().unwrap(), vec![name("28-000009eab19f")]);
+        storage.delete_calibration(&name("28-000009eab19f")).unwrap();
+        assert!(storage.get_list_of_calibrations().unwrap().is_empty());
+    }
+}